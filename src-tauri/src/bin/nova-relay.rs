@@ -0,0 +1,378 @@
+//! 独立中继, 无需 webview 即可在服务器上运行
+//!
+//! 构建: cargo build --release --no-default-features --bin nova-relay
+//!
+//! 用法: nova-relay [--port N] [--bind ADDR] [--secret HEX] [--join-password PW]
+//!                  [--max-connections N] [--max-payload BYTES] [--max-message BYTES]
+//!                  [--max-excludes N] [--queue-len N] [--register-timeout SECS]
+//!                  [--permit-timeout SECS]
+//!                  [--reassembly-timeout SECS] [--max-rooms N] [--local-only]
+//!                  [--max-spectators N] [--spectator-delay SECS]
+//!                  [--resume-grace SECS] [--migration-grace SECS] [--ping-interval SECS]
+//!                  [--idle-timeout SECS] [--server-idle-timeout SECS]
+//!                  [--rate-msgs N] [--rate-bytes N] [--ip-rate-msgs N] [--ip-rate-bytes N]
+//!                  [--rate-policy drop|warn|kick] [--compress-threshold BYTES]
+//!                  [--ban-file PATH] [--record PATH [--record-limit BYTES]]
+//!                  [--tls [--data-dir DIR] | --tls-cert PEM --tls-key PEM]
+
+use app_lib::config::RelayConfig;
+use app_lib::tls::TlsIdentity;
+use app_lib::util::decode_secret;
+use app_lib::{recorder, relay};
+use log::{error, LevelFilter, Log, Metadata, Record};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: nova-relay [OPTIONS]
+
+Options:
+  --port <PORT>              Listen port (default 25566)
+  --bind <ADDR>              Bind address (default 0.0.0.0)
+  --secret <HEX>             32-byte server secret as 64 hex chars (random if omitted)
+  --join-password <PW>       Require clients to present this password when registering
+  --max-connections <N>      Maximum concurrent sessions (default 64)
+  --max-payload <BYTES>      Maximum frame payload length (default 6144)
+  --max-message <BYTES>      Maximum length of a reassembled fragmented message (default 262144)
+  --max-excludes <N>         Maximum session ids excluded from one broadcast (default 16)
+  --queue-len <N>            Outgoing frames buffered per session (default 256)
+  --register-timeout <SECS>  Seconds a new connection has to send its register packet (default 5)
+  --permit-timeout <SECS>    Seconds a client waits for the server to let it in (default 4)
+  --reassembly-timeout <SECS> Seconds to collect all fragments of a message (default 10)
+  --max-rooms <N>            Maximum rooms besides the default one (default 16)
+  --max-spectators <N>       Maximum spectators across all rooms, not counted as players (default 16)
+  --spectator-delay <SECS>   Seconds spectators lag behind the live broadcast (default 0)
+  --resume-grace <SECS>      Seconds a dropped client may resume its session (default 10, 0 disables)
+  --migration-grace <SECS>   Seconds clients wait for a new server after the server drops (default 0, disabled)
+  --ping-interval <SECS>     Seconds between relay pings used to measure latency (default 5, 0 disables)
+  --idle-timeout <SECS>      Seconds without any frame or pong before a client is dropped (default 20, 0 disables)
+  --server-idle-timeout <SECS> Seconds without any frame or pong before a server is dropped (default 20, 0 disables)
+  --rate-msgs <N>            Client frames per second per session (default 0, unlimited)
+  --rate-bytes <N>           Client bytes per second per session (default 0, unlimited)
+  --ip-rate-msgs <N>         Client frames per second shared by one IP (default 0, unlimited)
+  --ip-rate-bytes <N>        Client bytes per second shared by one IP (default 0, unlimited)
+  --rate-policy <POLICY>     What to do with frames over the limit: drop, warn or kick (default warn)
+  --compress-threshold <N>   Compress broadcasts of at least N bytes for clients that opt in (default 1024, 0 disables)
+  --ban-file <PATH>          Persist bans of the default room to this file
+  --record <PATH>            Record relayed frames to this file
+  --record-limit <BYTES>     Maximum size of the recording (default 67108864)
+  --local-only               Only accept loopback connections
+  --tls                      Serve wss:// with a self-signed certificate kept in --data-dir
+  --data-dir <DIR>           Directory for the self-signed certificate (default .)
+  --tls-cert <PEM>           Serve wss:// with this certificate chain
+  --tls-key <PEM>            Private key for --tls-cert
+  -h, --help                 Print this help";
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+struct Args {
+    config: RelayConfig,
+    secret: Option<[u8; 32]>,
+    join_password: Option<String>,
+    record: Option<PathBuf>,
+    record_limit: u64,
+    local_only: bool,
+    tls: Option<TlsSource>,
+}
+
+enum TlsSource {
+    SelfSigned(PathBuf),
+    Pem(PathBuf, PathBuf),
+}
+
+fn parse_args() -> Result<Option<Args>, String> {
+    let mut config = RelayConfig::default();
+    let mut secret = None;
+    let mut join_password = None;
+    let mut record = None;
+    let mut record_limit = recorder::DEFAULT_MAX_BYTES;
+    let mut local_only = false;
+    let mut self_signed = false;
+    let mut data_dir = PathBuf::from(".");
+    let mut tls_cert = None;
+    let mut tls_key = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", name))
+        };
+
+        match flag.as_str() {
+            "--port" => {
+                config.port = value("--port")?
+                    .parse()
+                    .map_err(|_| "Invalid port".to_string())?;
+            }
+            "--bind" => {
+                config.bind_addr = value("--bind")?
+                    .parse::<IpAddr>()
+                    .map_err(|_| "Invalid bind address".to_string())?;
+            }
+            "--secret" => {
+                secret = Some(decode_secret(&value("--secret")?)?);
+            }
+            "--join-password" => join_password = Some(value("--join-password")?),
+            "--max-connections" => {
+                config.max_connections = value("--max-connections")?
+                    .parse()
+                    .map_err(|_| "Invalid connection limit".to_string())?;
+            }
+            "--max-payload" => {
+                config.max_payload_len = value("--max-payload")?
+                    .parse()
+                    .map_err(|_| "Invalid payload limit".to_string())?;
+            }
+            "--max-message" => {
+                config.max_message_len = value("--max-message")?
+                    .parse()
+                    .map_err(|_| "Invalid message limit".to_string())?;
+            }
+            "--max-excludes" => {
+                config.max_excludes = value("--max-excludes")?
+                    .parse()
+                    .map_err(|_| "Invalid exclude limit".to_string())?;
+            }
+            "--queue-len" => {
+                config.queue_len = value("--queue-len")?
+                    .parse()
+                    .map_err(|_| "Invalid queue length".to_string())?;
+            }
+            "--register-timeout" => {
+                let secs: u64 = value("--register-timeout")?
+                    .parse()
+                    .map_err(|_| "Invalid register timeout".to_string())?;
+                config.register_timeout = Duration::from_secs(secs);
+            }
+            "--permit-timeout" => {
+                let secs: u64 = value("--permit-timeout")?
+                    .parse()
+                    .map_err(|_| "Invalid permit timeout".to_string())?;
+                config.permit_timeout = Duration::from_secs(secs);
+            }
+            "--reassembly-timeout" => {
+                let secs: u64 = value("--reassembly-timeout")?
+                    .parse()
+                    .map_err(|_| "Invalid reassembly timeout".to_string())?;
+                config.reassembly_timeout = Duration::from_secs(secs);
+            }
+            "--max-rooms" => {
+                config.max_rooms = value("--max-rooms")?
+                    .parse()
+                    .map_err(|_| "Invalid room limit".to_string())?;
+            }
+            "--max-spectators" => {
+                config.max_spectators = value("--max-spectators")?
+                    .parse()
+                    .map_err(|_| "Invalid spectator limit".to_string())?;
+            }
+            "--spectator-delay" => {
+                let secs: u64 = value("--spectator-delay")?
+                    .parse()
+                    .map_err(|_| "Invalid spectator delay".to_string())?;
+                config.spectator_delay = Duration::from_secs(secs);
+            }
+            "--resume-grace" => {
+                let secs: u64 = value("--resume-grace")?
+                    .parse()
+                    .map_err(|_| "Invalid resume grace".to_string())?;
+                config.resume_grace = Duration::from_secs(secs);
+            }
+            "--migration-grace" => {
+                let secs: u64 = value("--migration-grace")?
+                    .parse()
+                    .map_err(|_| "Invalid migration grace".to_string())?;
+                config.migration_grace = Duration::from_secs(secs);
+            }
+            "--ping-interval" => {
+                let secs: u64 = value("--ping-interval")?
+                    .parse()
+                    .map_err(|_| "Invalid ping interval".to_string())?;
+                config.ping_interval = Duration::from_secs(secs);
+            }
+            "--idle-timeout" => {
+                let secs: u64 = value("--idle-timeout")?
+                    .parse()
+                    .map_err(|_| "Invalid idle timeout".to_string())?;
+                config.idle_timeout = Duration::from_secs(secs);
+            }
+            "--server-idle-timeout" => {
+                let secs: u64 = value("--server-idle-timeout")?
+                    .parse()
+                    .map_err(|_| "Invalid server idle timeout".to_string())?;
+                config.server_idle_timeout = Duration::from_secs(secs);
+            }
+            "--rate-msgs" => {
+                config.session_limit.messages_per_sec = parse_rate(value("--rate-msgs")?)?;
+            }
+            "--rate-bytes" => {
+                config.session_limit.bytes_per_sec = parse_rate(value("--rate-bytes")?)?;
+            }
+            "--ip-rate-msgs" => {
+                config.ip_limit.messages_per_sec = parse_rate(value("--ip-rate-msgs")?)?;
+            }
+            "--ip-rate-bytes" => {
+                config.ip_limit.bytes_per_sec = parse_rate(value("--ip-rate-bytes")?)?;
+            }
+            "--rate-policy" => {
+                config.limit_policy = value("--rate-policy")?.parse()?;
+            }
+            "--compress-threshold" => {
+                config.compress_threshold = value("--compress-threshold")?
+                    .parse()
+                    .map_err(|_| "Invalid compression threshold".to_string())?;
+            }
+            "--ban-file" => config.ban_file = Some(PathBuf::from(value("--ban-file")?)),
+            "--record" => record = Some(PathBuf::from(value("--record")?)),
+            "--record-limit" => {
+                record_limit = value("--record-limit")?
+                    .parse()
+                    .map_err(|_| "Invalid recording limit".to_string())?;
+            }
+            "--local-only" => local_only = true,
+            "--tls" => self_signed = true,
+            "--data-dir" => data_dir = PathBuf::from(value("--data-dir")?),
+            "--tls-cert" => tls_cert = Some(PathBuf::from(value("--tls-cert")?)),
+            "--tls-key" => tls_key = Some(PathBuf::from(value("--tls-key")?)),
+            "-h" | "--help" => return Ok(None),
+            other => return Err(format!("Unknown option \"{}\"", other)),
+        }
+    }
+
+    let tls = match (tls_cert, tls_key) {
+        (Some(cert), Some(key)) => Some(TlsSource::Pem(cert, key)),
+        (None, None) if self_signed => Some(TlsSource::SelfSigned(data_dir)),
+        (None, None) => None,
+        _ => return Err("--tls-cert and --tls-key must be given together".into()),
+    };
+
+    config.validate()?;
+    Ok(Some(Args {
+        config,
+        secret,
+        join_password,
+        record,
+        record_limit,
+        local_only,
+        tls,
+    }))
+}
+
+fn parse_rate(value: String) -> Result<u32, String> {
+    value.parse().map_err(|_| "Invalid rate limit".to_string())
+}
+
+fn encode_secret(secret: &[u8; 32]) -> String {
+    secret.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let _ = log::set_logger(&LOGGER).map(|()| log::set_max_level(LevelFilter::Info));
+
+    let args = match parse_args() {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    relay::set_open(!args.local_only);
+
+    let identity = match args.tls {
+        Some(TlsSource::SelfSigned(dir)) => TlsIdentity::load_or_generate(&dir).map(Some),
+        Some(TlsSource::Pem(cert, key)) => TlsIdentity::load_pem(&cert, &key).map(Some),
+        None => Ok(None),
+    };
+    let identity = match identity {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to load TLS certificate: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let started = relay::start_relay(
+        args.config,
+        args.secret,
+        identity.as_ref(),
+        args.join_password.as_deref(),
+    )
+    .await;
+    let (secret, mut task) = match started {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to start relay: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!("{}", encode_secret(&secret));
+    if let Some(identity) = identity.as_ref() {
+        println!("{}", identity.fingerprint());
+    }
+
+    if let Some(path) = args.record.as_ref() {
+        if let Err(e) = relay::start_recording(path, args.record_limit).await {
+            error!("Failed to start recording: {}", e);
+        }
+    }
+
+    tokio::select! {
+        _ = shutdown_signal() => {
+            relay::stop_recording().await;
+            relay::stop_relay().await;
+            let _ = task.await;
+            ExitCode::SUCCESS
+        }
+        // 未收到停止信号而自行结束, 如连续 accept 出错
+        _ = &mut task => {
+            error!("Relay stopped unexpectedly");
+            relay::stop_recording().await;
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Ctrl-C, unix 下还包括 systemctl stop / docker stop 发出的 SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => error!("Failed to listen for SIGTERM: {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,674 @@
+//! 录制回放工具, 用于复现多人联机问题
+//!
+//! 录制由中继完成 (nova-relay --record 或桌面端 start_recording), 本工具读取录制文件:
+//! 用法: nova-replay info <TRACE>
+//!       nova-replay server <TRACE> --url URL --secret HEX [--room CODE] [--speed X]
+//!                   [--max-payload BYTES]
+//!       nova-replay clients <TRACE> --url URL [--room CODE] [--target-room CODE]
+//!                   [--password PW] [--speed X] [--max-payload BYTES]
+
+use app_lib::auth::{password_digest, password_proof};
+use app_lib::config::DEFAULT_MAX_PAYLOAD_LEN;
+use app_lib::header::*;
+use app_lib::recorder::{read_recording, AttachInfo, Record, RecordKind, Recording};
+use app_lib::util::{decode_secret, read_var_uint};
+use app_lib::version::ProtocolVersion::{self, V1, V2};
+use bytes::BytesMut;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+use tokio_tungstenite::tungstenite::{Bytes, Message};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+const USAGE: &str = "\
+Usage: nova-replay <COMMAND> <TRACE> [OPTIONS]
+
+Traces are written by the relay (nova-relay --record, or start_recording in the app).
+
+Commands:
+  info                       Summarize the sessions in a trace
+  server                     Act as the recorded server toward live clients (spectator playback).
+                             Live clients are permitted automatically and receive the recorded
+                             broadcasts; unicast frames to recorded players are skipped
+  clients                    Act as the recorded clients toward a live server, replaying each
+                             client's frames with its newly assigned session id
+
+Options:
+  --url <URL>                Relay address, e.g. ws://127.0.0.1:25566
+  --secret <HEX>             Relay secret as 64 hex chars (server)
+  --room <CODE>              Room of the trace to replay (default 0)
+  --target-room <CODE>       Join this room of the live relay instead of the default one (clients)
+  --password <PW>            Join password of the live relay (clients)
+  --speed <X>                Playback speed factor (default 1.0)
+  --max-payload <BYTES>      Frame limit of the live relay; larger recorded messages are sent
+                             as fragments (default 6144)
+  -h, --help                 Print this help";
+
+/// 中继负载类型
+const ATTACHED: u8 = 0x01;
+const CLIENT_ATTACHED: u8 = 0x02;
+const AUTH_CHALLENGE: u8 = 0x10;
+
+/// 分片帧头: [0x20][message_id u16][index u16][count u16]
+const FRAGMENT_HEADER_LEN: usize = 7;
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type WsWriter = SplitSink<WsStream, Message>;
+type WsReader = SplitStream<WsStream>;
+
+enum Mode {
+    Info,
+    Server,
+    Clients,
+}
+
+struct Args {
+    mode: Mode,
+    trace: PathBuf,
+    url: Option<String>,
+    secret: Option<[u8; 32]>,
+    room: u32,
+    target_room: Option<u32>,
+    password: Option<String>,
+    speed: f64,
+    max_payload_len: usize,
+}
+
+fn parse_args() -> Result<Option<Args>, String> {
+    let mut args = std::env::args().skip(1);
+    let mode = match args.next().as_deref() {
+        Some("info") => Mode::Info,
+        Some("server") => Mode::Server,
+        Some("clients") => Mode::Clients,
+        Some("-h" | "--help") | None => return Ok(None),
+        Some(other) => return Err(format!("Unknown command \"{}\"", other)),
+    };
+    let trace = PathBuf::from(args.next().ok_or("Missing trace file")?);
+
+    let mut url = None;
+    let mut secret = None;
+    let mut room = 0;
+    let mut target_room = None;
+    let mut password = None;
+    let mut speed = 1.0;
+    let mut max_payload_len = DEFAULT_MAX_PAYLOAD_LEN;
+    while let Some(flag) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", name))
+        };
+
+        match flag.as_str() {
+            "--url" => url = Some(value("--url")?),
+            "--secret" => secret = Some(decode_secret(&value("--secret")?)?),
+            "--room" => {
+                room = value("--room")?
+                    .parse()
+                    .map_err(|_| "Invalid room code".to_string())?;
+            }
+            "--target-room" => {
+                let code = value("--target-room")?
+                    .parse()
+                    .map_err(|_| "Invalid room code".to_string())?;
+                target_room = Some(code);
+            }
+            "--password" => password = Some(value("--password")?),
+            "--speed" => {
+                speed = value("--speed")?
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|s| s.is_finite() && *s > 0.0)
+                    .ok_or("Speed must be a positive number")?;
+            }
+            "--max-payload" => {
+                max_payload_len = value("--max-payload")?
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|len| *len > FRAGMENT_HEADER_LEN)
+                    .ok_or("Invalid payload limit")?;
+            }
+            "-h" | "--help" => return Ok(None),
+            other => return Err(format!("Unknown option \"{}\"", other)),
+        }
+    }
+
+    match mode {
+        Mode::Info => {}
+        Mode::Server if url.is_none() || secret.is_none() => {
+            return Err("server needs --url and --secret".into());
+        }
+        Mode::Clients if url.is_none() => return Err("clients needs --url".into()),
+        _ => {}
+    }
+
+    Ok(Some(Args {
+        mode,
+        trace,
+        url,
+        secret,
+        room,
+        target_room,
+        password,
+        speed,
+        max_payload_len,
+    }))
+}
+
+/// 录制中的一个会话. 同一 session id 断开后再次接入视为新会话,
+/// 会话恢复产生的重复接入记录沿用原会话
+struct TracedSession {
+    session_id: u16,
+    info: AttachInfo,
+    attached_ms: u32,
+    detached_ms: Option<u32>,
+    frames: Vec<(u32, Vec<u8>)>,
+}
+
+fn collect_sessions(recording: &Recording, room: u32) -> Vec<TracedSession> {
+    let mut sessions: Vec<TracedSession> = Vec::new();
+    let mut live: HashMap<u16, usize> = HashMap::new();
+    for record in recording.records.iter().filter(|r| r.room == room) {
+        match record.kind {
+            RecordKind::Attach => {
+                if live.contains_key(&record.session_id) {
+                    continue;
+                }
+                let Some(info) = AttachInfo::parse(&record.data) else {
+                    continue;
+                };
+                live.insert(record.session_id, sessions.len());
+                sessions.push(TracedSession {
+                    session_id: record.session_id,
+                    info,
+                    attached_ms: record.elapsed_ms,
+                    detached_ms: None,
+                    frames: Vec::new(),
+                });
+            }
+            RecordKind::Detach => {
+                if let Some(index) = live.remove(&record.session_id) {
+                    sessions[index].detached_ms = Some(record.elapsed_ms);
+                }
+            }
+            RecordKind::ClientFrame | RecordKind::ServerFrame => {
+                if let Some(&index) = live.get(&record.session_id) {
+                    sessions[index]
+                        .frames
+                        .push((record.elapsed_ms, record.data.clone()));
+                }
+            }
+        }
+    }
+    sessions
+}
+
+/// 按回放速度换算录制时间点
+struct Clock {
+    start: Instant,
+    base_ms: u32,
+    speed: f64,
+}
+
+impl Clock {
+    fn due(&self, elapsed_ms: u32) -> Instant {
+        let offset = elapsed_ms.saturating_sub(self.base_ms) as f64 / 1000.0 / self.speed;
+        self.start + Duration::from_secs_f64(offset)
+    }
+}
+
+/// 旁观回放只保留面向所有人的帧, 排除列表里是录制时的玩家, 对旁观者无意义
+fn spectator_frame(version: ProtocolVersion, frame: &[u8]) -> Option<Vec<u8>> {
+    match *frame.first()? {
+        SERVER_BROADCAST => Some(frame.to_vec()),
+        SERVER_CLASSED => {
+            let header_len = if *frame.get(1)? == CLASS_LATEST { 4 } else { 2 };
+            spectator_frame(version, frame.get(header_len..)?)
+        }
+        SERVER_EXCLUDE => {
+            let (id, rest) = version.read_id(&frame[1..])?;
+            let (count, rest) = read_var_uint(rest).ok()?;
+            let data = rest.get(count as usize * version.id_len()..)?;
+            let mut out = BytesMut::from(&[SERVER_BROADCAST][..]);
+            version.put_id(&mut out, id);
+            out.extend_from_slice(data);
+            Some(out.into())
+        }
+        _ => None,
+    }
+}
+
+/// 将录制中的 C2S 帧改写为实际分配的 session id 与协议版本
+fn client_frame(
+    recorded: ProtocolVersion,
+    live: ProtocolVersion,
+    session_id: u16,
+    frame: &[u8],
+) -> Option<Vec<u8>> {
+    if frame.first() != Some(&C2S) {
+        return Some(frame.to_vec());
+    }
+    let (_, body) = recorded.read_id(&frame[1..])?;
+    let mut out = BytesMut::from(&[C2S][..]);
+    live.put_id(&mut out, session_id);
+    out.extend_from_slice(body);
+    Some(out.into())
+}
+
+/// 录制的是中继重组后的消息, 超过在线中继单帧上限的按分片重新切分
+fn fragment(frame: Vec<u8>, max_payload_len: usize, next_id: &mut u16) -> Vec<Vec<u8>> {
+    if frame.len() <= max_payload_len {
+        return vec![frame];
+    }
+    let message_id = *next_id;
+    *next_id = next_id.wrapping_add(1);
+    let chunks = frame.chunks(max_payload_len - FRAGMENT_HEADER_LEN);
+    let count = chunks.len() as u16;
+    chunks
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut part = Vec::with_capacity(FRAGMENT_HEADER_LEN + chunk.len());
+            part.push(FRAGMENT);
+            part.extend_from_slice(&message_id.to_le_bytes());
+            part.extend_from_slice(&(index as u16).to_le_bytes());
+            part.extend_from_slice(&count.to_le_bytes());
+            part.extend_from_slice(chunk);
+            part
+        })
+        .collect()
+}
+
+async fn connect(url: &str) -> Result<(WsWriter, WsReader), String> {
+    let (ws, _) = connect_async(url)
+        .await
+        .map_err(|e| format!("Failed to connect {}: {}", url, e))?;
+    Ok(ws.split())
+}
+
+/// 等待中继的 Attached, 期间收到的其它中继消息原样打印
+async fn wait_attached(reader: &mut WsReader, version: ProtocolVersion) -> Result<u16, String> {
+    while let Some(msg) = reader.next().await {
+        match msg.map_err(|e| e.to_string())? {
+            Message::Binary(payload) if payload.len() >= 2 && payload[0] == 0x00 => {
+                if payload[1] == ATTACHED {
+                    return version
+                        .read_id(&payload[2..])
+                        .map(|(id, _)| id)
+                        .ok_or_else(|| "Invalid Attached payload".to_string());
+                }
+                eprintln!("Relay: {}", describe_payload(&payload));
+            }
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    Err("Connection closed before attaching".into())
+}
+
+/// 以入房密码注册并等待 Attached: 收到挑战时重发注册包并附上 password_proof.
+/// 房间未设密码时中继直接 Attached
+async fn attach_with_password(
+    writer: &mut WsWriter,
+    reader: &mut WsReader,
+    register: &[u8],
+    password: &str,
+) -> Result<u16, String> {
+    while let Some(msg) = reader.next().await {
+        match msg.map_err(|e| e.to_string())? {
+            Message::Binary(payload) if payload.len() >= 2 && payload[0] == 0x00 => {
+                match payload[1] {
+                    ATTACHED => {
+                        return V2
+                            .read_id(&payload[2..])
+                            .map(|(id, _)| id)
+                            .ok_or_else(|| "Invalid Attached payload".to_string())
+                    }
+                    AUTH_CHALLENGE if payload.len() == 34 => {
+                        let salt: [u8; 16] = payload[2..18].try_into().unwrap();
+                        let nonce: [u8; 16] = payload[18..34].try_into().unwrap();
+                        let digest = password_digest(&salt, password.as_bytes());
+                        let proof = password_proof(&nonce, &digest);
+                        // 把首包的 ProofLen 0 换成应答
+                        let mut answer = register[..register.len() - 1].to_vec();
+                        answer.push(proof.len() as u8);
+                        answer.extend_from_slice(&proof);
+                        writer
+                            .send(Message::Binary(answer.into()))
+                            .await
+                            .map_err(|e| e.to_string())?;
+                    }
+                    _ => eprintln!("Relay: {}", describe_payload(&payload)),
+                }
+            }
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    Err("Connection closed before attaching".into())
+}
+
+fn describe_payload(payload: &[u8]) -> String {
+    // RelayMessage 为文本, 其余按十六进制输出
+    if payload[1] == 0x03 && payload.len() > 4 {
+        return String::from_utf8_lossy(&payload[4..]).into_owned();
+    }
+    payload
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn print_info(recording: &Recording) {
+    let duration = recording.records.last().map_or(0, |r| r.elapsed_ms);
+    println!(
+        "Recorded at {} ms, {} records over {:.1} s",
+        recording.started_at,
+        recording.records.len(),
+        duration as f64 / 1000.0
+    );
+
+    let mut rooms: BTreeMap<u32, (usize, usize, usize)> = BTreeMap::new();
+    for record in &recording.records {
+        let entry = rooms.entry(record.room).or_default();
+        match record.kind {
+            RecordKind::ClientFrame => entry.0 += 1,
+            RecordKind::ServerFrame => entry.1 += 1,
+            _ => {}
+        }
+        entry.2 += record.data.len();
+    }
+
+    for (room, (client_frames, server_frames, bytes)) in rooms {
+        println!(
+            "Room {}: {} client frames, {} server frames, {} bytes",
+            room, client_frames, server_frames, bytes
+        );
+        for session in collect_sessions(recording, room) {
+            let role = if session.info.is_server {
+                "server"
+            } else if session.info.is_spectator {
+                "spectator"
+            } else {
+                "client"
+            };
+            let detached = session
+                .detached_ms
+                .map_or_else(|| "end".to_string(), |ms| format!("{} ms", ms));
+            println!(
+                "  {} {} (v{}) {} ms - {}, {} frames",
+                role,
+                session.session_id,
+                session.info.version.number(),
+                session.attached_ms,
+                detached,
+                session.frames.len()
+            );
+        }
+    }
+}
+
+async fn replay_server(args: &Args, recording: &Recording) -> Result<(), String> {
+    let records: Vec<&Record> = recording
+        .records
+        .iter()
+        .filter(|r| r.room == args.room && r.kind == RecordKind::ServerFrame)
+        .collect();
+    let Some(first) = records.first() else {
+        return Err(format!("No server frames recorded in room {}", args.room));
+    };
+
+    // 录制开始前已接入的服务端没有接入记录, 按 v1 处理
+    let version = recording
+        .records
+        .iter()
+        .filter(|r| r.room == args.room && r.kind == RecordKind::Attach)
+        .filter_map(|r| AttachInfo::parse(&r.data))
+        .find(|info| info.is_server)
+        .map_or(V1, |info| info.version);
+
+    let url = args.url.as_deref().unwrap_or_default();
+    let (mut writer, mut reader) = connect(url).await?;
+    let mut register = vec![REG_SERVER];
+    if version == V2 {
+        register.push(V2.number());
+    }
+    register.extend_from_slice(&args.secret.unwrap_or_default());
+    writer
+        .send(Message::Binary(register.into()))
+        .await
+        .map_err(|e| e.to_string())?;
+    let session_id = wait_attached(&mut reader, version).await?;
+    println!("Attached as server {}", session_id);
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<Bytes>();
+    let send_task = tokio::spawn(async move {
+        while let Some(frame) = rx.recv().await {
+            if writer.send(Message::Binary(frame)).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.close().await;
+    });
+
+    // 自动放行接入的旁观客户端
+    let permit_tx = tx.clone();
+    let read_task = tokio::spawn(async move {
+        while let Some(Ok(msg)) = reader.next().await {
+            let Message::Binary(payload) = msg else {
+                continue;
+            };
+            if payload.len() < 2 || payload[0] != 0x00 || payload[1] != CLIENT_ATTACHED {
+                continue;
+            }
+            let Some((id, _)) = version.read_id(&payload[2..]) else {
+                continue;
+            };
+            println!("Client {} attached", id);
+            let mut permit = BytesMut::from(&[SERVER_ACTION, PERMIT][..]);
+            version.put_id(&mut permit, id);
+            let _ = permit_tx.send(permit.freeze());
+        }
+    });
+
+    let clock = Clock {
+        start: Instant::now(),
+        base_ms: first.elapsed_ms,
+        speed: args.speed,
+    };
+    let mut sent = 0usize;
+    let mut message_id = 0u16;
+    'replay: for record in &records {
+        let Some(frame) = spectator_frame(version, &record.data) else {
+            continue;
+        };
+        sleep_until(clock.due(record.elapsed_ms)).await;
+        for part in fragment(frame, args.max_payload_len, &mut message_id) {
+            if tx.send(part.into()).is_err() {
+                break 'replay;
+            }
+        }
+        sent += 1;
+    }
+    println!("Replayed {} of {} server frames", sent, records.len());
+
+    drop(tx);
+    read_task.abort();
+    let _ = send_task.await;
+    Ok(())
+}
+
+async fn replay_clients(args: &Args, recording: &Recording) -> Result<(), String> {
+    let sessions: Vec<TracedSession> = collect_sessions(recording, args.room)
+        .into_iter()
+        .filter(|s| !s.info.is_server && !s.info.is_spectator && s.info.uuid.is_some())
+        .collect();
+    let Some(base_ms) = sessions.iter().map(|s| s.attached_ms).min() else {
+        return Err(format!("No clients recorded in room {}", args.room));
+    };
+
+    let clock = Arc::new(Clock {
+        start: Instant::now(),
+        base_ms,
+        speed: args.speed,
+    });
+    let mut tasks = Vec::new();
+    for session in sessions {
+        let url = args.url.clone().unwrap_or_default();
+        let target_room = args.target_room;
+        let password = args.password.clone();
+        let max_payload_len = args.max_payload_len;
+        let clock = clock.clone();
+        tasks.push(tokio::spawn(async move {
+            let recorded_id = session.session_id;
+            let replayed = replay_client(
+                &url,
+                session,
+                &clock,
+                target_room,
+                password,
+                max_payload_len,
+            );
+            if let Err(e) = replayed.await {
+                eprintln!("Client {}: {}", recorded_id, e);
+            }
+        }));
+    }
+    for task in tasks {
+        let _ = task.await;
+    }
+    Ok(())
+}
+
+async fn replay_client(
+    url: &str,
+    session: TracedSession,
+    clock: &Clock,
+    target_room: Option<u32>,
+    password: Option<String>,
+    max_payload_len: usize,
+) -> Result<(), String> {
+    sleep_until(clock.due(session.attached_ms)).await;
+
+    // 携带密码的注册包仅有 v2 形式, 首包不带应答
+    let recorded = session.info.version;
+    let version = if password.is_some() { V2 } else { recorded };
+    let uuid = session.info.uuid.unwrap_or_default();
+    let register = match (&password, target_room) {
+        (Some(_), room) => {
+            let mut p = vec![REG_AUTH_CLIENT, V2.number()];
+            p.extend_from_slice(&room.unwrap_or(0).to_le_bytes());
+            p.extend_from_slice(&uuid);
+            p.push(0);
+            p
+        }
+        (None, Some(room)) => {
+            let mut p = vec![REG_ROOM_CLIENT];
+            if version == V2 {
+                p.push(V2.number());
+            }
+            p.extend_from_slice(&room.to_le_bytes());
+            p.extend_from_slice(&uuid);
+            p
+        }
+        (None, None) => {
+            let mut p = vec![REG_CLIENT];
+            if version == V2 {
+                p.push(V2.number());
+            }
+            p.extend_from_slice(&uuid);
+            p
+        }
+    };
+
+    let (mut writer, mut reader) = connect(url).await?;
+    writer
+        .send(Message::Binary(register.clone().into()))
+        .await
+        .map_err(|e| e.to_string())?;
+    let session_id = match password {
+        Some(password) => {
+            attach_with_password(&mut writer, &mut reader, &register, &password).await?
+        }
+        None => wait_attached(&mut reader, version).await?,
+    };
+    println!("Client {} attached as {}", session.session_id, session_id);
+
+    // 持续读取, 使中继的发送队列不被占满
+    let read_task = tokio::spawn(async move { while let Some(Ok(_)) = reader.next().await {} });
+
+    let mut sent = 0usize;
+    let mut message_id = 0u16;
+    for (elapsed_ms, data) in &session.frames {
+        let Some(frame) = client_frame(recorded, version, session_id, data) else {
+            continue;
+        };
+        sleep_until(clock.due(*elapsed_ms)).await;
+        for part in fragment(frame, max_payload_len, &mut message_id) {
+            writer
+                .send(Message::Binary(part.into()))
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        sent += 1;
+    }
+
+    if let Some(detached_ms) = session.detached_ms {
+        sleep_until(clock.due(detached_ms)).await;
+    }
+    let _ = writer.close().await;
+    read_task.abort();
+    println!(
+        "Client {} replayed {} of {} frames",
+        session.session_id,
+        sent,
+        session.frames.len()
+    );
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let recording = match read_recording(&args.trace) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = match args.mode {
+        Mode::Info => {
+            print_info(&recording);
+            Ok(())
+        }
+        Mode::Server => replay_server(&args, &recording).await,
+        Mode::Clients => replay_clients(&args, &recording).await,
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
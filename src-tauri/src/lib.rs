@@ -1,14 +1,25 @@
+#[cfg(feature = "desktop")]
 use crate::file::chose_dir;
-use crate::network::cmd::{is_open, set_open, start_server, stop_server};
+#[cfg(feature = "desktop")]
+use crate::network::cmd::{
+    is_open, relay_stats, set_join_password, set_open, start_recording, start_secure_server,
+    start_server, stop_recording, stop_server,
+};
+#[cfg(feature = "desktop")]
 use crate::network::discovery::cmd::{
     is_lan_sniffing, list_lan_servers, start_lan_announce, start_lan_sniff, stop_lan_announce,
     stop_lan_sniff,
 };
 
+#[cfg(feature = "desktop")]
 mod file;
 mod network;
+#[cfg(feature = "desktop")]
 mod window;
 
+pub use network::{auth, config, header, recorder, relay, stats, tls, util, version};
+
+#[cfg(feature = "desktop")]
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
@@ -41,6 +52,11 @@ pub fn run() {
         })
         .invoke_handler(tauri::generate_handler![
             start_server,
+            start_secure_server,
+            relay_stats,
+            set_join_password,
+            start_recording,
+            stop_recording,
             stop_server,
             set_open,
             is_open,
@@ -0,0 +1,71 @@
+use crate::network::util::constant_time_eq;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// 入房密码, 仅保存加盐摘要.
+/// 对端以挑战-应答证明知道密码: 中继下发 salt 与随机 nonce,
+/// 对端回复 password_proof(nonce, password_digest(salt, 密码)), 密码不经过网络
+#[derive(Clone, Copy)]
+pub struct PasswordHash {
+    salt: [u8; 16],
+    digest: [u8; 32],
+}
+
+impl PasswordHash {
+    pub fn new(password: &str) -> Self {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Self {
+            salt,
+            digest: password_digest(&salt, password.as_bytes()),
+        }
+    }
+
+    /// 由对端在本地算好的摘要构造, 用于房主创建房间时设置密码
+    pub fn from_digest(salt: [u8; 16], digest: [u8; 32]) -> Self {
+        Self { salt, digest }
+    }
+
+    /// 空密码视为未设置
+    pub fn from_option(password: Option<&str>) -> Option<Self> {
+        password.filter(|p| !p.is_empty()).map(Self::new)
+    }
+
+    pub fn salt(&self) -> &[u8; 16] {
+        &self.salt
+    }
+
+    pub fn verify_proof(&self, nonce: &[u8; 16], proof: &[u8]) -> bool {
+        constant_time_eq(&password_proof(nonce, &self.digest), proof)
+    }
+}
+
+/// SHA-256(salt ‖ 密码)
+pub fn password_digest(salt: &[u8; 16], password: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(password);
+    hasher.finalize().into()
+}
+
+/// SHA-256(nonce ‖ 摘要), 对挑战的应答
+pub fn password_proof(nonce: &[u8; 16], digest: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(nonce);
+    hasher.update(digest);
+    hasher.finalize().into()
+}
+
+/// 一次挑战用的随机 nonce
+pub(crate) fn challenge_nonce() -> [u8; 16] {
+    let mut nonce = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+impl fmt::Debug for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PasswordHash(..)")
+    }
+}
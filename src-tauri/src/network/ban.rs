@@ -0,0 +1,256 @@
+use crate::network::util::parse_ipv4;
+use log::warn;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+
+/// 封禁的地址段, 主机位已清零
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Self> {
+        let addr = addr.to_canonical();
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix > max {
+            return None;
+        }
+        Some(Self {
+            addr: mask(addr, prefix),
+            prefix,
+        })
+    }
+
+    pub fn single(addr: IpAddr) -> Self {
+        let addr = addr.to_canonical();
+        let prefix = if addr.is_ipv4() { 32 } else { 128 };
+        Self { addr, prefix }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// IPv4 映射的 IPv6 地址按 IPv4 匹配
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        ip.is_ipv4() == self.addr.is_ipv4() && mask(ip, self.prefix) == self.addr
+    }
+
+    /// 解析 BAN_IP/UNBAN_IP 的数据, 按长度区分:
+    /// 4 = IPv4 (u32 LE, 旧格式), 5 = IPv4 + 前缀长度,
+    /// 16 = IPv6 (网络字节序), 17 = IPv6 + 前缀长度
+    pub fn parse_action(data: &[u8]) -> Option<Self> {
+        match data.len() {
+            4 => Some(Self::single(parse_ipv4(data)?.into())),
+            5 => Self::new(parse_ipv4(data)?.into(), data[4]),
+            16 | 17 => {
+                let octets: [u8; 16] = data[..16].try_into().ok()?;
+                let addr = IpAddr::V6(Ipv6Addr::from(octets));
+                let prefix = data.get(16).copied().unwrap_or(128);
+                Self::new(addr, prefix)
+            }
+            _ => None,
+        }
+    }
+
+    /// v1 服务端沿用旧格式: 任意不少于 4 字节的数据按前 4 字节的 IPv4 封禁单个地址.
+    /// 先按 parse_action 解析, 失败时再退回旧格式
+    pub fn parse_action_v1(data: &[u8]) -> Option<Self> {
+        Self::parse_action(data).or_else(|| Some(Self::single(parse_ipv4(data)?.into())))
+    }
+}
+
+fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4);
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            IpAddr::V4((bits & mask).into())
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6);
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            IpAddr::V6((bits & mask).into())
+        }
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    /// "1.2.3.4", "1.2.3.0/24", "2001:db8::/32"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("Invalid address \"{}\"", s))?;
+        match prefix {
+            None => Ok(Self::single(addr)),
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .and_then(|prefix| Self::new(addr, prefix))
+                .ok_or_else(|| format!("Invalid prefix \"{}\"", s)),
+        }
+    }
+}
+
+/// 读取封禁列表文件, 每行一个地址段, '#' 之后为注释; 文件不存在时为空
+pub fn load_bans(path: &Path) -> Result<Vec<IpRange>, String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+
+    let mut bans = Vec::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.parse() {
+            Ok(range) => bans.push(range),
+            Err(e) => warn!("Skipping ban entry in {}: {}", path.display(), e),
+        }
+    }
+    Ok(bans)
+}
+
+pub async fn save_bans(path: &Path, bans: &[IpRange]) -> Result<(), String> {
+    let mut text = String::new();
+    for range in bans {
+        text.push_str(&range.to_string());
+        text.push('\n');
+    }
+
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    tokio::fs::write(path, text)
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_prefix_matching() {
+        let range: IpRange = "10.1.2.3/16".parse().unwrap();
+        assert_eq!(range.to_string(), "10.1.0.0/16");
+        assert!(range.contains(&ip("10.1.255.1")));
+        assert!(!range.contains(&ip("10.2.0.1")));
+        assert!(range.contains(&ip("::ffff:10.1.0.9")));
+        assert!(!range.contains(&ip("::a01:9")));
+    }
+
+    #[test]
+    fn ipv6_prefix_matching() {
+        let range: IpRange = "2001:db8:ffff::1/32".parse().unwrap();
+        assert_eq!(range.to_string(), "2001:db8::/32");
+        assert!(range.contains(&ip("2001:db8:1::1")));
+        assert!(!range.contains(&ip("2001:db9::1")));
+        assert!(!range.contains(&ip("32.1.13.184")));
+    }
+
+    #[test]
+    fn zero_prefix_matches_whole_family() {
+        let v4: IpRange = "1.2.3.4/0".parse().unwrap();
+        assert_eq!(v4.addr(), ip("0.0.0.0"));
+        assert!(v4.contains(&ip("255.255.255.255")));
+        assert!(!v4.contains(&ip("::1")));
+
+        let v6: IpRange = "2001:db8::/0".parse().unwrap();
+        assert!(v6.contains(&ip("ffff::1")));
+        assert!(!v6.contains(&ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn full_prefix_matches_single_address() {
+        let v4: IpRange = "1.2.3.4/32".parse().unwrap();
+        assert_eq!(v4, IpRange::single(ip("1.2.3.4")));
+        assert!(v4.contains(&ip("1.2.3.4")));
+        assert!(!v4.contains(&ip("1.2.3.5")));
+
+        let v6: IpRange = "2001:db8::1/128".parse().unwrap();
+        assert_eq!(v6, IpRange::single(ip("2001:db8::1")));
+        assert!(v6.contains(&ip("2001:db8::1")));
+        assert!(!v6.contains(&ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn rejects_overlong_prefix() {
+        assert!("1.2.3.4/33".parse::<IpRange>().is_err());
+        assert!("::1/129".parse::<IpRange>().is_err());
+        assert!("1.2.3.4/x".parse::<IpRange>().is_err());
+        assert!(IpRange::new(ip("1.2.3.4"), 33).is_none());
+
+        // BAN_IP 数据: IPv4 为 u32 LE
+        assert!(IpRange::parse_action(&[4, 3, 2, 1, 33]).is_none());
+        let mut v6 = [0u8; 17];
+        v6[16] = 129;
+        assert!(IpRange::parse_action(&v6).is_none());
+        assert_eq!(
+            IpRange::parse_action(&[0, 3, 2, 1, 24]),
+            "1.2.3.0/24".parse().ok()
+        );
+    }
+
+    #[test]
+    fn v1_action_falls_back_to_ipv4_prefix() {
+        let single = Some(IpRange::single(ip("1.2.3.4")));
+        assert_eq!(IpRange::parse_action_v1(&[4, 3, 2, 1, 33]), single);
+        assert_eq!(IpRange::parse_action_v1(&[4, 3, 2, 1, 0, 0, 0]), single);
+        assert!(IpRange::parse_action(&[4, 3, 2, 1, 0, 0, 0]).is_none());
+        assert_eq!(
+            IpRange::parse_action_v1(&[0, 3, 2, 1, 24]),
+            "1.2.3.0/24".parse().ok()
+        );
+        assert!(IpRange::parse_action_v1(&[4, 3, 2]).is_none());
+    }
+
+    #[tokio::test]
+    async fn save_load_round_trip() {
+        let path = std::env::temp_dir().join(format!("bans-{}.txt", std::process::id()));
+        let bans: Vec<IpRange> = ["1.2.3.4", "10.0.0.0/8", "2001:db8::/32", "::1"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+
+        save_bans(&path, &bans).await.unwrap();
+        let loaded = load_bans(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, bans);
+        assert!(load_bans(&path).unwrap().is_empty());
+    }
+}
@@ -1,88 +1,215 @@
-use crate::network::states::{RelayState, ServerHandle, ServerManager};
-use crate::network::wss::{run_ws_server, OPEN_FLAG, SERVER_MANAGER};
-use log::{error, info};
-use rand::RngCore;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::sync::{oneshot, Mutex};
+use crate::network::config::RelayConfig;
+use crate::network::recorder::{self, RecordingSummary};
+use crate::network::relay;
+use crate::network::stats::RelayStats;
+use crate::network::tls::TlsIdentity;
+use crate::network::util::now_ms;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tauri::Manager;
 
-#[tauri::command]
-pub async fn start_server(port: u16) -> Result<[u8; 32], String> {
-    let state_cell = SERVER_MANAGER
-        .get_or_init(|| async {
-            Mutex::new(ServerManager {
-                handle: None,
-                stop_tx: None,
-            })
-        })
-        .await;
-
-    let mut guard = state_cell.lock().await;
-    if let Some(handle) = guard.handle.as_ref() {
-        return Err(format!("Server already listen on \"{}\"", handle.port));
-    }
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SecureServerInfo {
+    pub secret: [u8; 32],
+    /// 证书 SHA-256 指纹, 供客户端固定证书
+    pub fingerprint: String,
+}
 
-    let (tx, rx) = oneshot::channel::<()>();
-    guard.stop_tx = Some(tx);
+/// 前端可调整的中继参数, 未给出的项使用默认值.
+/// 时长以秒计, 配额为 0 表示不限制, 含义同 nova-relay 的同名参数
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RelayOptions {
+    pub bind_addr: Option<IpAddr>,
+    pub max_connections: Option<usize>,
+    pub max_payload_len: Option<usize>,
+    pub max_message_len: Option<usize>,
+    pub max_excludes: Option<usize>,
+    pub queue_len: Option<usize>,
+    pub register_timeout_secs: Option<u64>,
+    pub permit_timeout_secs: Option<u64>,
+    pub reassembly_timeout_secs: Option<u64>,
+    pub max_rooms: Option<usize>,
+    pub max_spectators: Option<usize>,
+    pub spectator_delay_secs: Option<u64>,
+    pub resume_grace_secs: Option<u64>,
+    pub migration_grace_secs: Option<u64>,
+    pub ping_interval_secs: Option<u64>,
+    pub idle_timeout_secs: Option<u64>,
+    pub server_idle_timeout_secs: Option<u64>,
+    pub session_messages_per_sec: Option<u32>,
+    pub session_bytes_per_sec: Option<u32>,
+    pub ip_messages_per_sec: Option<u32>,
+    pub ip_bytes_per_sec: Option<u32>,
+    /// "drop", "warn" 或 "kick"
+    pub limit_policy: Option<String>,
+    pub compress_threshold: Option<usize>,
+}
 
-    // 开始监听
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = match TcpListener::bind(&addr).await {
-        Ok(l) => l,
-        Err(e) => {
-            error!("Failed to bind {}: {}", addr, e);
-            return Err("Port already in use".into());
+impl RelayOptions {
+    fn apply(self, config: &mut RelayConfig) -> Result<(), String> {
+        fn set<T>(field: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+        fn set_secs(field: &mut Duration, secs: Option<u64>) {
+            set(field, secs.map(Duration::from_secs));
         }
-    };
-
-    info!("WebSocket server listening on {}", addr);
 
-    // 生成随机密钥
-    let mut secret = [0u8; 32];
-    rand::thread_rng().fill_bytes(&mut secret);
+        set(&mut config.bind_addr, self.bind_addr);
+        set(&mut config.max_connections, self.max_connections);
+        set(&mut config.max_payload_len, self.max_payload_len);
+        set(&mut config.max_message_len, self.max_message_len);
+        set(&mut config.max_excludes, self.max_excludes);
+        set(&mut config.queue_len, self.queue_len);
+        set_secs(&mut config.register_timeout, self.register_timeout_secs);
+        set_secs(&mut config.permit_timeout, self.permit_timeout_secs);
+        set_secs(&mut config.reassembly_timeout, self.reassembly_timeout_secs);
+        set(&mut config.max_rooms, self.max_rooms);
+        set(&mut config.max_spectators, self.max_spectators);
+        set_secs(&mut config.spectator_delay, self.spectator_delay_secs);
+        set_secs(&mut config.resume_grace, self.resume_grace_secs);
+        set_secs(&mut config.migration_grace, self.migration_grace_secs);
+        set_secs(&mut config.ping_interval, self.ping_interval_secs);
+        set_secs(&mut config.idle_timeout, self.idle_timeout_secs);
+        set_secs(
+            &mut config.server_idle_timeout,
+            self.server_idle_timeout_secs,
+        );
+        set(
+            &mut config.session_limit.messages_per_sec,
+            self.session_messages_per_sec,
+        );
+        set(
+            &mut config.session_limit.bytes_per_sec,
+            self.session_bytes_per_sec,
+        );
+        set(
+            &mut config.ip_limit.messages_per_sec,
+            self.ip_messages_per_sec,
+        );
+        set(&mut config.ip_limit.bytes_per_sec, self.ip_bytes_per_sec);
+        if let Some(policy) = self.limit_policy {
+            config.limit_policy = policy.parse()?;
+        }
+        set(&mut config.compress_threshold, self.compress_threshold);
+        Ok(())
+    }
+}
 
-    let state = Arc::new(RelayState::new());
-    tokio::spawn(run_ws_server(listener, state, rx));
+const BAN_FILE: &str = "bans.txt";
+const RECORDING_DIR: &str = "recordings";
 
-    guard.handle = Some(ServerHandle { port, secret });
+/// 桌面端中继配置, 封禁列表保存在应用数据目录
+fn app_config(
+    app: &tauri::AppHandle,
+    port: u16,
+    options: Option<RelayOptions>,
+) -> Result<RelayConfig, String> {
+    let dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    let mut config = RelayConfig {
+        ban_file: Some(dir.join(BAN_FILE)),
+        ..RelayConfig::with_port(port)
+    };
+    if let Some(options) = options {
+        options.apply(&mut config)?;
+    }
+    Ok(config)
+}
 
+/// 启动中继, 参数非法时返回校验错误
+#[tauri::command]
+pub async fn start_server(
+    app: tauri::AppHandle,
+    port: u16,
+    password: Option<String>,
+    options: Option<RelayOptions>,
+) -> Result<[u8; 32], String> {
+    let config = app_config(&app, port, options)?;
+    let (secret, _) = relay::start_relay(config, None, None, password.as_deref()).await?;
     Ok(secret)
 }
 
+/// 以 wss:// 启动中继.
+/// 未提供证书路径时使用应用数据目录下的自签名证书
 #[tauri::command]
-pub async fn stop_server() -> Result<bool, String> {
-    let Some(state_cell) = SERVER_MANAGER.get() else {
-        info!("Server not initialized");
-        return Ok(false);
+pub async fn start_secure_server(
+    app: tauri::AppHandle,
+    port: u16,
+    cert_path: Option<String>,
+    key_path: Option<String>,
+    password: Option<String>,
+    options: Option<RelayOptions>,
+) -> Result<SecureServerInfo, String> {
+    let identity = match (cert_path, key_path) {
+        (Some(cert), Some(key)) => TlsIdentity::load_pem(Path::new(&cert), Path::new(&key))?,
+        (None, None) => {
+            let dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+            TlsIdentity::load_or_generate(&dir)?
+        }
+        _ => return Err("Certificate and key must be provided together".into()),
     };
 
-    let mut guard = state_cell.lock().await;
-    if let Some(tx) = guard.stop_tx.take() {
-        let _ = tx.send(());
-        info!("Stopping server");
-        Ok(true)
+    let config = app_config(&app, port, options)?;
+    let (secret, _) =
+        relay::start_relay(config, None, Some(&identity), password.as_deref()).await?;
+    Ok(SecureServerInfo {
+        secret,
+        fingerprint: identity.fingerprint(),
+    })
+}
+
+#[tauri::command]
+pub async fn stop_server() -> Result<bool, String> {
+    Ok(relay::stop_relay().await)
+}
+
+/// 设置或清除入房密码
+#[tauri::command]
+pub async fn set_join_password(password: Option<String>) -> Result<(), String> {
+    if relay::set_join_password(password.as_deref()).await {
+        Ok(())
     } else {
-        info!("Server not running");
-        Ok(false)
+        Err("Server not running".into())
     }
 }
 
+/// 开始录制中继流量, 返回录制文件路径
+#[tauri::command]
+pub async fn start_recording(
+    app: tauri::AppHandle,
+    max_bytes: Option<u64>,
+) -> Result<PathBuf, String> {
+    let dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    let path = dir
+        .join(RECORDING_DIR)
+        .join(format!("relay-{}.nrec", now_ms()));
+    let max_bytes = max_bytes.unwrap_or(recorder::DEFAULT_MAX_BYTES);
+    relay::start_recording(&path, max_bytes).await?;
+    Ok(path)
+}
+
+#[tauri::command]
+pub async fn stop_recording() -> Result<Option<RecordingSummary>, String> {
+    Ok(relay::stop_recording().await)
+}
+
+#[tauri::command]
+pub async fn relay_stats() -> Result<RelayStats, String> {
+    relay::stats()
+        .await
+        .ok_or_else(|| "Server not running".to_string())
+}
+
 #[tauri::command]
 pub fn set_open(bl: bool) -> bool {
-    if let Some(flag) = OPEN_FLAG.get() {
-        flag.store(bl, Ordering::SeqCst);
-        true
-    } else {
-        false
-    }
+    relay::set_open(bl)
 }
 
 #[tauri::command]
 pub fn is_open() -> bool {
-    if let Some(flag) = OPEN_FLAG.get() {
-        return flag.load(Ordering::SeqCst);
-    }
-
-    false
+    relay::is_open()
 }
@@ -0,0 +1,17 @@
+use crate::network::header::COMPRESSED;
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// 压缩帧: [0x30][原始帧长度 u32 LE][LZ4 块]
+/// 解压得到的原始帧与未压缩时收到的完全一致.
+/// 压缩后不比原帧小时返回 None
+pub fn compress_frame(frame: &[u8]) -> Option<Bytes> {
+    let compressed = lz4_flex::block::compress_prepend_size(frame);
+    if 1 + compressed.len() >= frame.len() {
+        return None;
+    }
+
+    let mut buf = BytesMut::with_capacity(1 + compressed.len());
+    buf.put_u8(COMPRESSED);
+    buf.put_slice(&compressed);
+    Some(buf.freeze())
+}
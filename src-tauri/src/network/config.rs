@@ -0,0 +1,187 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const DEFAULT_PORT: u16 = 25566;
+pub const DEFAULT_MAX_PAYLOAD_LEN: usize = 6144; // 6 KB upper bound for a single frame
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64; // fits v1 (u8) session ids with margin
+pub const DEFAULT_MAX_EXCLUDES: usize = 16; // session ids in one SERVER_EXCLUDE frame
+pub const DEFAULT_QUEUE_LEN: usize = 256; // outgoing frames buffered per session
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 256 * 1024; // reassembled fragments
+pub const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_MAX_SPECTATORS: usize = 16; // across all rooms, separate from max_connections
+pub const DEFAULT_MAX_ROOMS: usize = 16; // rooms besides the default one
+pub const DEFAULT_RESUME_GRACE: Duration = Duration::from_secs(10);
+pub const DEFAULT_COMPRESS_THRESHOLD: usize = 1024;
+pub const DEFAULT_MIGRATION_GRACE: Duration = Duration::ZERO;
+pub const DEFAULT_REGISTER_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_PERMIT_TIMEOUT: Duration = Duration::from_secs(4);
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(5);
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(20); // several missed pongs
+
+/// 令牌桶配额, 桶容量为一秒的量; 0 表示不限制
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub messages_per_sec: u32,
+    pub bytes_per_sec: u32,
+}
+
+impl RateLimit {
+    pub const UNLIMITED: RateLimit = RateLimit {
+        messages_per_sec: 0,
+        bytes_per_sec: 0,
+    };
+
+    pub fn is_unlimited(&self) -> bool {
+        self.messages_per_sec == 0 && self.bytes_per_sec == 0
+    }
+}
+
+/// 客户端超出配额时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// 静默丢弃超额帧
+    Drop,
+    /// 丢弃超额帧, 并以 RelayMessage 提醒客户端
+    Warn,
+    /// 断开客户端
+    Kick,
+}
+
+impl FromStr for LimitPolicy {
+    type Err = String;
+
+    /// "drop", "warn", "kick"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(LimitPolicy::Drop),
+            "warn" => Ok(LimitPolicy::Warn),
+            "kick" => Ok(LimitPolicy::Kick),
+            _ => Err("Rate policy must be drop, warn or kick".into()),
+        }
+    }
+}
+
+/// 中继运行参数
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    pub bind_addr: IpAddr,
+    pub port: u16,
+    pub max_connections: usize,
+    pub max_payload_len: usize,
+    /// 单个排除广播帧中排除的 session id 上限
+    pub max_excludes: usize,
+    /// 每个会话发送管道的容量, 丢弃余量与拥塞水位按其比例计算
+    pub queue_len: usize,
+    /// 分片重组后单条消息的长度上限
+    pub max_message_len: usize,
+    /// 分片消息须在此时长内收齐
+    pub reassembly_timeout: Duration,
+    pub max_rooms: usize,
+    /// 旁观者总数上限, 不占用 max_connections
+    pub max_spectators: usize,
+    /// 旁观者收到广播的延迟, 为 0 时实时转发
+    pub spectator_delay: Duration,
+    /// 连接建立后须在此时长内发来注册包
+    pub register_timeout: Duration,
+    /// 客户端注册后等待服务端放行的时长, 超时即断开
+    pub permit_timeout: Duration,
+    /// 掉线客户端保留席位的时长, 为 0 时不支持会话恢复
+    pub resume_grace: Duration,
+    /// 服务端掉线后保留客户端等待新服务端接管的时长, 为 0 时不支持房主迁移
+    pub migration_grace: Duration,
+    /// 中继向每个连接发送 ping 的间隔, 用于测量往返时延; 为 0 时不发送
+    pub ping_interval: Duration,
+    /// 客户端与旁观者超过此时长未发来任何帧 (含 pong) 即断开; 为 0 时不检测
+    pub idle_timeout: Duration,
+    /// 服务端的空闲超时, 含义同 idle_timeout
+    pub server_idle_timeout: Duration,
+    /// 每个客户端会话的 C2S 配额, 默认不限制
+    pub session_limit: RateLimit,
+    /// 同一 IP 所有客户端共享的 C2S 配额, 默认不限制
+    pub ip_limit: RateLimit,
+    pub limit_policy: LimitPolicy,
+    /// 不小于此长度的广播帧压缩后发给声明支持压缩的客户端; 为 0 时不压缩
+    pub compress_threshold: usize,
+    /// 默认房间封禁列表的持久化文件, 启动时读取, 变更时写回
+    pub ban_file: Option<PathBuf>,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: DEFAULT_PORT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_payload_len: DEFAULT_MAX_PAYLOAD_LEN,
+            max_excludes: DEFAULT_MAX_EXCLUDES,
+            queue_len: DEFAULT_QUEUE_LEN,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            reassembly_timeout: DEFAULT_REASSEMBLY_TIMEOUT,
+            max_rooms: DEFAULT_MAX_ROOMS,
+            max_spectators: DEFAULT_MAX_SPECTATORS,
+            spectator_delay: Duration::ZERO,
+            register_timeout: DEFAULT_REGISTER_TIMEOUT,
+            permit_timeout: DEFAULT_PERMIT_TIMEOUT,
+            resume_grace: DEFAULT_RESUME_GRACE,
+            migration_grace: DEFAULT_MIGRATION_GRACE,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            server_idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            session_limit: RateLimit::UNLIMITED,
+            ip_limit: RateLimit::UNLIMITED,
+            limit_policy: LimitPolicy::Warn,
+            compress_threshold: DEFAULT_COMPRESS_THRESHOLD,
+            ban_file: None,
+        }
+    }
+}
+
+impl RelayConfig {
+    pub fn with_port(port: u16) -> Self {
+        Self {
+            port,
+            ..Self::default()
+        }
+    }
+
+    pub fn should_compress(&self, frame_len: usize) -> bool {
+        self.compress_threshold != 0 && frame_len >= self.compress_threshold
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        // session id 最大为 u16, 且 0 保留; 超过 254 时 v1 对端可能分配不到 id
+        if self.max_connections == 0 || self.max_connections >= u16::MAX as usize {
+            return Err(format!("max_connections must be in 1..{}", u16::MAX));
+        }
+        if self.max_payload_len < 3 {
+            return Err("max_payload_len must be at least 3 bytes".into());
+        }
+        if self.max_message_len < self.max_payload_len {
+            return Err("max_message_len must not be smaller than max_payload_len".into());
+        }
+        if self.max_excludes == 0 {
+            return Err("max_excludes must be at least 1".into());
+        }
+        // 水位按容量比例计算, 过小时无法区分
+        if self.queue_len < 16 {
+            return Err("queue_len must be at least 16".into());
+        }
+        if self.register_timeout.is_zero() || self.permit_timeout.is_zero() {
+            return Err("register_timeout and permit_timeout must not be zero".into());
+        }
+        // 空闲的对端只靠 pong 续期, 超时须长于 ping 间隔
+        if !self.ping_interval.is_zero() {
+            for (name, idle) in [
+                ("idle_timeout", self.idle_timeout),
+                ("server_idle_timeout", self.server_idle_timeout),
+            ] {
+                if !idle.is_zero() && idle <= self.ping_interval {
+                    return Err(format!("{} must be longer than ping_interval", name));
+                }
+            }
+        }
+        Ok(())
+    }
+}
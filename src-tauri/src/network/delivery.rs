@@ -0,0 +1,89 @@
+use crate::network::header::{CLASS_DROPPABLE, CLASS_LATEST, CLASS_RELIABLE, SERVER_CLASSED};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// 服务端为帧声明的投递类别
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Delivery {
+    /// 队列紧张时丢弃, 未声明类别的帧按此处理
+    Droppable,
+    /// 必须送达, 无法入队时断开接收方
+    Reliable,
+    /// 可丢弃, 且同一键尚未写出的旧帧由新帧替换
+    Latest(u16),
+}
+
+impl Delivery {
+    /// 拆开 [SERVER_CLASSED][class u8][key u16 仅 Latest][frame], 返回类别与内层帧
+    pub fn split(payload: &Bytes) -> Option<(Delivery, Bytes)> {
+        if payload.first() != Some(&SERVER_CLASSED) || payload.len() < 2 {
+            return None;
+        }
+        let (delivery, header_len) = match payload[1] {
+            CLASS_DROPPABLE => (Delivery::Droppable, 2),
+            CLASS_RELIABLE => (Delivery::Reliable, 2),
+            CLASS_LATEST if payload.len() >= 4 => {
+                let key = u16::from_le_bytes([payload[2], payload[3]]);
+                (Delivery::Latest(key), 4)
+            }
+            _ => return None,
+        };
+        Some((delivery, payload.slice(header_len..)))
+    }
+}
+
+/// 会话管道中的一项
+#[derive(Debug)]
+pub(crate) enum Outgoing {
+    Frame(Bytes),
+    /// 按键合并的帧, 写出时取槽中最新的值
+    Latest(Arc<LatestSlot>),
+}
+
+impl Outgoing {
+    /// 取出要写出的帧, 合并槽已被清空时返回 None
+    pub fn into_frame(self) -> Option<Bytes> {
+        match self {
+            Outgoing::Frame(frame) => Some(frame),
+            Outgoing::Latest(slot) => slot.take(),
+        }
+    }
+}
+
+impl From<Bytes> for Outgoing {
+    fn from(frame: Bytes) -> Self {
+        Outgoing::Frame(frame)
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct LatestSlot(Mutex<Option<Bytes>>);
+
+impl LatestSlot {
+    pub fn take(&self) -> Option<Bytes> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+}
+
+/// 单个接收方按键合并的待写出帧
+#[derive(Debug, Default)]
+pub(crate) struct LatestSlots(Mutex<HashMap<u16, Arc<LatestSlot>>>);
+
+impl LatestSlots {
+    /// 同一键已有未写出的帧时就地替换并返回 None, 否则返回需要入队的新槽
+    pub fn replace(&self, key: u16, frame: Bytes) -> Option<Arc<LatestSlot>> {
+        let mut slots = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(slot) = slots.get(&key) {
+            let mut pending = slot.0.lock().unwrap_or_else(|e| e.into_inner());
+            if pending.is_some() {
+                *pending = Some(frame);
+                return None;
+            }
+        }
+
+        let slot = Arc::new(LatestSlot(Mutex::new(Some(frame))));
+        slots.insert(key, slot.clone());
+        Some(slot)
+    }
+}
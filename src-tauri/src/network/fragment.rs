@@ -0,0 +1,260 @@
+use crate::network::notice::NoticeCode;
+use bytes::{Bytes, BytesMut};
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+/// 单个连接同时重组中的消息数上限
+const MAX_PARTIALS: usize = 4;
+/// 单条消息的分片数上限
+const MAX_FRAGMENTS: usize = 1024;
+
+/// 分片帧: [0x20][message_id u16][index u16][count u16][chunk]
+/// 各分片的 chunk 按 index 拼接后即为一个完整的普通帧, 由中继照常路由
+const FRAGMENT_HEADER_LEN: usize = 7;
+
+struct Partial {
+    chunks: Vec<Option<Bytes>>,
+    received: usize,
+    len: usize,
+    started: Instant,
+}
+
+/// 单个连接的分片重组状态, 仅由该连接的读取任务访问.
+/// 超时在收到新分片时检查, 滞留的消息数受 MAX_PARTIALS 限制
+pub(crate) struct Reassembly {
+    partials: HashMap<u16, Partial>,
+    max_message_len: usize,
+    timeout: Duration,
+}
+
+impl Reassembly {
+    pub fn new(max_message_len: usize, timeout: Duration) -> Self {
+        Self {
+            partials: HashMap::new(),
+            max_message_len,
+            timeout,
+        }
+    }
+
+    /// 移除超时的消息, 返回其 message id
+    pub fn expire(&mut self) -> Vec<u16> {
+        let timeout = self.timeout;
+        let expired: Vec<u16> = self
+            .partials
+            .iter()
+            .filter(|(_, p)| p.started.elapsed() >= timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &expired {
+            self.partials.remove(id);
+        }
+        expired
+    }
+
+    /// 收到一个分片, 消息完整时返回重组后的帧.
+    /// 出错时丢弃该消息已收到的全部分片
+    pub fn push(&mut self, frame: &[u8]) -> Result<Option<Bytes>, NoticeCode> {
+        if frame.len() <= FRAGMENT_HEADER_LEN {
+            return Err(NoticeCode::InvalidFragment);
+        }
+        let message_id = u16::from_le_bytes([frame[1], frame[2]]);
+        let index = u16::from_le_bytes([frame[3], frame[4]]) as usize;
+        let count = u16::from_le_bytes([frame[5], frame[6]]) as usize;
+        let chunk = &frame[FRAGMENT_HEADER_LEN..];
+
+        let result = self.insert(message_id, index, count, chunk);
+        if result.is_err() {
+            self.partials.remove(&message_id);
+        }
+        result
+    }
+
+    fn insert(
+        &mut self,
+        message_id: u16,
+        index: usize,
+        count: usize,
+        chunk: &[u8],
+    ) -> Result<Option<Bytes>, NoticeCode> {
+        if index >= count {
+            return Err(NoticeCode::InvalidFragment);
+        }
+        if count > MAX_FRAGMENTS {
+            return Err(NoticeCode::MessageTooLarge);
+        }
+
+        if !self.partials.contains_key(&message_id) {
+            if self.partials.len() >= MAX_PARTIALS {
+                return Err(NoticeCode::MessageTooLarge);
+            }
+            self.partials.insert(
+                message_id,
+                Partial {
+                    chunks: vec![None; count],
+                    received: 0,
+                    len: 0,
+                    started: Instant::now(),
+                },
+            );
+        }
+
+        let Some(partial) = self.partials.get_mut(&message_id) else {
+            return Err(NoticeCode::InvalidFragment);
+        };
+        if partial.chunks.len() != count || partial.chunks[index].is_some() {
+            return Err(NoticeCode::InvalidFragment);
+        }
+
+        partial.len += chunk.len();
+        if partial.len > self.max_message_len {
+            return Err(NoticeCode::MessageTooLarge);
+        }
+        partial.chunks[index] = Some(Bytes::copy_from_slice(chunk));
+        partial.received += 1;
+        if partial.received < count {
+            return Ok(None);
+        }
+
+        let Some(partial) = self.partials.remove(&message_id) else {
+            return Ok(None);
+        };
+        let mut message = BytesMut::with_capacity(partial.len);
+        for chunk in partial.chunks.into_iter().flatten() {
+            message.extend_from_slice(&chunk);
+        }
+        Ok(Some(message.freeze()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(message_id: u16, index: u16, count: u16, chunk: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x20];
+        frame.extend_from_slice(&message_id.to_le_bytes());
+        frame.extend_from_slice(&index.to_le_bytes());
+        frame.extend_from_slice(&count.to_le_bytes());
+        frame.extend_from_slice(chunk);
+        frame
+    }
+
+    fn reassembly() -> Reassembly {
+        Reassembly::new(1 << 20, Duration::from_secs(60))
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let mut r = reassembly();
+        assert_eq!(r.push(&fragment(7, 2, 3, b"ef")), Ok(None));
+        assert_eq!(r.push(&fragment(7, 0, 3, b"ab")), Ok(None));
+        let message = r.push(&fragment(7, 1, 3, b"cd")).unwrap();
+        assert_eq!(message.as_deref(), Some(&b"abcdef"[..]));
+        assert!(r.partials.is_empty());
+    }
+
+    #[test]
+    fn interleaved_messages_stay_separate() {
+        let mut r = reassembly();
+        assert_eq!(r.push(&fragment(1, 0, 2, b"a")), Ok(None));
+        assert_eq!(r.push(&fragment(2, 1, 2, b"y")), Ok(None));
+        let first = r.push(&fragment(1, 1, 2, b"b")).unwrap();
+        let second = r.push(&fragment(2, 0, 2, b"x")).unwrap();
+        assert_eq!(first.as_deref(), Some(&b"ab"[..]));
+        assert_eq!(second.as_deref(), Some(&b"xy"[..]));
+    }
+
+    #[test]
+    fn duplicate_fragment_drops_message() {
+        let mut r = reassembly();
+        assert_eq!(r.push(&fragment(3, 0, 2, b"a")), Ok(None));
+        assert_eq!(
+            r.push(&fragment(3, 0, 2, b"a")),
+            Err(NoticeCode::InvalidFragment)
+        );
+        assert!(r.partials.is_empty());
+
+        // 丢弃后同一 id 重新开始
+        assert_eq!(r.push(&fragment(3, 1, 2, b"b")), Ok(None));
+        assert_eq!(r.partials[&3].received, 1);
+    }
+
+    #[test]
+    fn rejects_inconsistent_headers() {
+        let mut r = reassembly();
+        assert_eq!(
+            r.push(&[0x20, 0, 0, 0, 0, 1, 0]),
+            Err(NoticeCode::InvalidFragment)
+        );
+        assert_eq!(
+            r.push(&fragment(1, 2, 2, b"a")),
+            Err(NoticeCode::InvalidFragment)
+        );
+        assert_eq!(r.push(&fragment(1, 0, 2, b"a")), Ok(None));
+        assert_eq!(
+            r.push(&fragment(1, 1, 3, b"b")),
+            Err(NoticeCode::InvalidFragment)
+        );
+        assert!(r.partials.is_empty());
+    }
+
+    #[test]
+    fn limits_concurrent_messages() {
+        let mut r = reassembly();
+        for id in 0..MAX_PARTIALS as u16 {
+            assert_eq!(r.push(&fragment(id, 0, 2, b"a")), Ok(None));
+        }
+        let id = MAX_PARTIALS as u16;
+        assert_eq!(
+            r.push(&fragment(id, 0, 2, b"a")),
+            Err(NoticeCode::MessageTooLarge)
+        );
+        assert_eq!(r.partials.len(), MAX_PARTIALS);
+
+        // 已在重组中的消息不受影响, 完成后腾出名额
+        assert!(r.push(&fragment(0, 1, 2, b"b")).unwrap().is_some());
+        assert_eq!(r.push(&fragment(id, 0, 2, b"a")), Ok(None));
+    }
+
+    #[test]
+    fn limits_fragment_count() {
+        let mut r = reassembly();
+        let max = MAX_FRAGMENTS as u16;
+        assert_eq!(r.push(&fragment(1, 0, max, b"a")), Ok(None));
+        assert_eq!(
+            r.push(&fragment(2, 0, max + 1, b"a")),
+            Err(NoticeCode::MessageTooLarge)
+        );
+        assert_eq!(r.partials.len(), 1);
+    }
+
+    #[test]
+    fn limits_message_len() {
+        let mut r = Reassembly::new(4, Duration::from_secs(60));
+        assert_eq!(r.push(&fragment(1, 0, 3, b"ab")), Ok(None));
+        assert_eq!(r.push(&fragment(1, 1, 3, b"cd")), Ok(None));
+        assert_eq!(
+            r.push(&fragment(1, 2, 3, b"e")),
+            Err(NoticeCode::MessageTooLarge)
+        );
+        assert!(r.partials.is_empty());
+
+        let message = r.push(&fragment(2, 0, 1, b"abcd")).unwrap();
+        assert_eq!(message.as_deref(), Some(&b"abcd"[..]));
+    }
+
+    #[test]
+    fn expires_stale_messages() {
+        let mut r = reassembly();
+        assert_eq!(r.push(&fragment(1, 0, 2, b"a")), Ok(None));
+        assert!(r.expire().is_empty());
+
+        let mut r = Reassembly::new(1 << 20, Duration::ZERO);
+        assert_eq!(r.push(&fragment(1, 0, 2, b"a")), Ok(None));
+        assert_eq!(r.push(&fragment(2, 0, 2, b"a")), Ok(None));
+        let mut expired = r.expire();
+        expired.sort_unstable();
+        assert_eq!(expired, [1, 2]);
+        assert!(r.partials.is_empty());
+    }
+}
@@ -1,19 +1,59 @@
 /// 连接注册
 pub const REG_SERVER: u8 = 0x01;
 pub const REG_CLIENT: u8 = 0x02;
+pub const REG_ROOM: u8 = 0x03;
+pub const REG_ROOM_CLIENT: u8 = 0x04;
+pub const REG_RESUME: u8 = 0x05;
+pub const REG_AUTH_CLIENT: u8 = 0x06;
+pub const REG_MIGRATE: u8 = 0x07;
+pub const REG_SPECTATOR: u8 = 0x08;
 
 pub const C2S: u8 = 0x10;
 
+/// 大消息的分片, 由中继重组后照常路由
+pub const FRAGMENT: u8 = 0x20;
+
+/// 中继压缩后下发给客户端的帧
+pub const COMPRESSED: u8 = 0x30;
+
 /// 服务端头
 pub const SERVER_BROADCAST: u8 = 0x11;
 pub const SERVER_SINGLE: u8 = 0x12;
 pub const SERVER_SINGLE_UUID: u8 = 0x13;
 pub const SERVER_EXCLUDE: u8 = 0x14;
+/// [0x15][class][key u16 仅 CLASS_LATEST][服务端帧], 为内层帧声明投递类别
+pub const SERVER_CLASSED: u8 = 0x15;
+/// [0x16][id][group u16][data], 转发给分组内的客户端
+pub const SERVER_GROUP: u8 = 0x16;
 pub const SERVER_ACTION: u8 = 0xFF;
 
+/// 投递类别, 未声明类别的帧视为可丢弃
+pub const CLASS_DROPPABLE: u8 = 0x00;
+pub const CLASS_RELIABLE: u8 = 0x01;
+pub const CLASS_LATEST: u8 = 0x02;
+
 /// 中继控制命令
 pub const KICK: u8 = 0x00;
 pub const PERMIT: u8 = 0x01;
 pub const QUERY: u8 = 0x02;
 pub const BAN_IP: u8 = 0x03;
 pub const UNBAN_IP: u8 = 0x04;
+pub const QUERY_LIMITS: u8 = 0x05;
+pub const QUERY_STATS: u8 = 0x06;
+pub const QUERY_BANS: u8 = 0x07;
+pub const GROUP_JOIN: u8 = 0x08;
+pub const GROUP_LEAVE: u8 = 0x09;
+pub const GROUP_REMOVE: u8 = 0x0A;
+pub const KICK_WITH_REASON: u8 = 0x0B;
+pub const BAN_SESSION: u8 = 0x0C;
+pub const DENY: u8 = 0x0D;
+
+/// v2 客户端注册包末尾可选的能力位
+pub const CAP_COMPRESSION: u8 = 0x01;
+
+/// QueryClients 扩展字段标志
+pub const QUERY_RTT: u8 = 0x01;
+pub const QUERY_STATE: u8 = 0x02;
+pub const QUERY_ADDRESS: u8 = 0x04;
+pub const QUERY_TIMING: u8 = 0x08;
+pub const QUERY_QUEUE: u8 = 0x10;
@@ -0,0 +1,236 @@
+use crate::network::config::RateLimit;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// 令牌桶, 容量为一秒的配额
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Option<Self> {
+        if rate == 0 {
+            return None;
+        }
+        Some(Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        })
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    /// 超过桶容量的单帧在桶满时放行, 之后以欠额方式补足
+    fn has(&self, cost: f64) -> bool {
+        self.tokens >= cost.min(self.rate)
+    }
+}
+
+/// 一组消息数与字节数配额
+pub(crate) struct Quota {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl Quota {
+    pub fn new(limit: &RateLimit) -> Self {
+        Self {
+            messages: TokenBucket::new(limit.messages_per_sec),
+            bytes: TokenBucket::new(limit.bytes_per_sec),
+        }
+    }
+
+    fn check(&mut self, now: Instant, len: usize) -> bool {
+        let mut ok = true;
+        if let Some(bucket) = self.messages.as_mut() {
+            bucket.refill(now);
+            ok &= bucket.has(1.0);
+        }
+        if let Some(bucket) = self.bytes.as_mut() {
+            bucket.refill(now);
+            ok &= bucket.has(len as f64);
+        }
+        ok
+    }
+
+    fn consume(&mut self, len: usize) {
+        if let Some(bucket) = self.messages.as_mut() {
+            bucket.tokens -= 1.0;
+        }
+        if let Some(bucket) = self.bytes.as_mut() {
+            bucket.tokens -= len as f64;
+        }
+    }
+}
+
+impl std::fmt::Debug for Quota {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Quota").finish_non_exhaustive()
+    }
+}
+
+pub(crate) type SharedQuota = Arc<Mutex<Quota>>;
+
+/// 单个客户端的限流状态与超额计数
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    session: Mutex<Quota>,
+    ip: Option<SharedQuota>,
+    limited_frames: AtomicU64,
+    limited_bytes: AtomicU64,
+    warned: AtomicBool,
+}
+
+impl Limiter {
+    pub fn new(limit: &RateLimit, ip: Option<SharedQuota>) -> Self {
+        Self {
+            session: Mutex::new(Quota::new(limit)),
+            ip,
+            limited_frames: AtomicU64::new(0),
+            limited_bytes: AtomicU64::new(0),
+            warned: AtomicBool::new(false),
+        }
+    }
+
+    /// 会话与 IP 配额都足够时扣除并放行, 否则只计数不扣除
+    pub fn admit(&self, len: usize) -> bool {
+        let now = Instant::now();
+        let mut session = self.session.lock().unwrap_or_else(|e| e.into_inner());
+        let mut ip = self
+            .ip
+            .as_ref()
+            .map(|q| q.lock().unwrap_or_else(|e| e.into_inner()));
+
+        let session_ok = session.check(now, len);
+        let ip_ok = ip.as_mut().map_or(true, |q| q.check(now, len));
+        if session_ok && ip_ok {
+            session.consume(len);
+            if let Some(q) = ip.as_mut() {
+                q.consume(len);
+            }
+            self.warned.store(false, Ordering::Relaxed);
+            return true;
+        }
+
+        self.limited_frames.fetch_add(1, Ordering::Relaxed);
+        self.limited_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        false
+    }
+
+    /// 每段连续超额只提醒一次
+    pub fn should_warn(&self) -> bool {
+        !self.warned.swap(true, Ordering::Relaxed)
+    }
+
+    /// (超额帧数, 超额字节数)
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.limited_frames.load(Ordering::Relaxed),
+            self.limited_bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limit(messages_per_sec: u32, bytes_per_sec: u32) -> RateLimit {
+        RateLimit {
+            messages_per_sec,
+            bytes_per_sec,
+        }
+    }
+
+    fn admit(quota: &mut Quota, now: Instant, len: usize) -> bool {
+        let ok = quota.check(now, len);
+        if ok {
+            quota.consume(len);
+        }
+        ok
+    }
+
+    #[test]
+    fn bucket_starts_full_and_allows_one_second_burst() {
+        let mut quota = Quota::new(&limit(3, 0));
+        let now = Instant::now();
+        assert!(admit(&mut quota, now, 1));
+        assert!(admit(&mut quota, now, 1));
+        assert!(admit(&mut quota, now, 1));
+        assert!(!admit(&mut quota, now, 1));
+    }
+
+    #[test]
+    fn bucket_refills_at_rate() {
+        let mut quota = Quota::new(&limit(4, 0));
+        let start = Instant::now();
+        for _ in 0..4 {
+            assert!(admit(&mut quota, start, 1));
+        }
+        assert!(!admit(&mut quota, start + Duration::from_millis(200), 1));
+        assert!(admit(&mut quota, start + Duration::from_millis(300), 1));
+        assert!(!admit(&mut quota, start + Duration::from_millis(300), 1));
+    }
+
+    #[test]
+    fn refill_is_capped_at_one_second() {
+        let mut quota = Quota::new(&limit(2, 0));
+        let later = Instant::now() + Duration::from_secs(10);
+        assert!(admit(&mut quota, later, 1));
+        assert!(admit(&mut quota, later, 1));
+        assert!(!admit(&mut quota, later, 1));
+    }
+
+    #[test]
+    fn oversized_frame_admitted_when_full_then_repaid() {
+        let mut quota = Quota::new(&limit(0, 100));
+        let start = Instant::now();
+        assert!(admit(&mut quota, start, 250));
+        // 欠 150 字节, 1.5 秒后才回到 0
+        assert!(!admit(&mut quota, start + Duration::from_millis(1500), 1));
+        assert!(!admit(&mut quota, start + Duration::from_millis(2400), 250));
+        assert!(admit(&mut quota, start + Duration::from_millis(2600), 250));
+    }
+
+    #[test]
+    fn message_and_byte_limits_both_apply() {
+        let mut quota = Quota::new(&limit(10, 100));
+        let now = Instant::now();
+        assert!(admit(&mut quota, now, 60));
+        assert!(!admit(&mut quota, now, 60));
+        // 被拒的帧不扣除配额
+        assert!(admit(&mut quota, now, 40));
+    }
+
+    #[test]
+    fn shared_ip_quota_limits_all_sessions() {
+        let ip = Arc::new(Mutex::new(Quota::new(&limit(2, 0))));
+        let a = Limiter::new(&limit(5, 0), Some(ip.clone()));
+        let b = Limiter::new(&limit(5, 0), Some(ip));
+        assert!(a.admit(10));
+        assert!(b.admit(10));
+        assert!(!a.admit(10));
+        assert!(!b.admit(20));
+        assert_eq!(a.stats(), (1, 10));
+        assert_eq!(b.stats(), (1, 20));
+    }
+
+    #[test]
+    fn warns_once_per_limited_streak() {
+        let limiter = Limiter::new(&limit(1, 0), None);
+        assert!(limiter.admit(1));
+        assert!(!limiter.admit(1));
+        assert!(limiter.should_warn());
+        assert!(!limiter.admit(1));
+        assert!(!limiter.should_warn());
+    }
+}
@@ -1,8 +1,25 @@
+pub mod auth;
+mod ban;
+#[cfg(feature = "desktop")]
 pub mod cmd;
+mod compress;
+pub mod config;
+mod delivery;
+#[cfg(feature = "desktop")]
 pub mod discovery;
+mod fragment;
+pub mod header;
+mod limit;
+mod notice;
 mod protocol;
+pub mod recorder;
+pub mod relay;
 mod session;
+mod spectator;
 mod states;
-mod util;
+pub mod stats;
+mod stream;
+pub mod tls;
+pub mod util;
+pub mod version;
 mod wss;
-mod header;
@@ -0,0 +1,154 @@
+/// 中继通知码, 数值一经发布不得更改.
+/// 1xxx 注册, 2xxx 会话, 3xxx 中继操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum NoticeCode {
+    InvalidRegister = 1000,
+    ServerExists = 1001,
+    InvalidSecret = 1002,
+    RoomLimit = 1003,
+    RoomNotFound = 1004,
+    DuplicatePlayer = 1005,
+    Banned = 1006,
+    ResumeFailed = 1007,
+    PasswordRequired = 1008,
+    WrongPassword = 1009,
+    MigrationFailed = 1010,
+    SpectatorLimit = 1011,
+    ConnectionLimit = 1012,
+    UnaddressableClients = 1013,
+
+    PayloadTooLarge = 2000,
+    RateLimited = 2001,
+    RateLimitKicked = 2002,
+    /// 参数: 踢出原因, 可选
+    Kicked = 2003,
+    ExcludeTooLarge = 2004,
+    HostMigrating = 2005,
+    HostMigrated = 2006,
+    MessageTooLarge = 2007,
+    /// 参数: 消息 id
+    FragmentTimeout = 2008,
+    InvalidFragment = 2009,
+    SpectatorReadOnly = 2010,
+    /// 参数: 拒绝原因, 可选
+    Denied = 2011,
+    RelayShutdown = 2012,
+
+    InvalidAction = 3000,
+    UnknownAction = 3001,
+    /// 参数: 操作名
+    MissingSessionId = 3002,
+    /// 地址或前缀长度无效
+    InvalidIp = 3003,
+    Unbanned = 3004,
+    NotBanned = 3005,
+    InvalidQueryFlags = 3006,
+    InvalidGroup = 3007,
+    GroupLimit = 3008,
+    SessionNotFound = 3009,
+    NotPending = 3010,
+    InvalidReason = 3011,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum NoticeLevel {
+    Info = 0,
+    Warn = 1,
+    Error = 2,
+}
+
+impl NoticeCode {
+    pub fn level(self) -> NoticeLevel {
+        match self {
+            NoticeCode::Kicked
+            | NoticeCode::Unbanned
+            | NoticeCode::NotBanned
+            | NoticeCode::HostMigrating
+            | NoticeCode::HostMigrated
+            | NoticeCode::RelayShutdown => NoticeLevel::Info,
+            NoticeCode::RateLimited | NoticeCode::FragmentTimeout => NoticeLevel::Warn,
+            _ => NoticeLevel::Error,
+        }
+    }
+
+    /// 旧版文本, v1 对端依赖其中的 "ERR:" 等前缀
+    fn text(self) -> &'static str {
+        match self {
+            NoticeCode::InvalidRegister => "ERR:Invalid register packet",
+            NoticeCode::ServerExists => "ERR:Server already registered",
+            NoticeCode::InvalidSecret => "ERR:Invalid secret",
+            NoticeCode::RoomLimit => "ERR:Room limit reached",
+            NoticeCode::RoomNotFound => "ERR:Room not found",
+            NoticeCode::DuplicatePlayer => "ERR:Duplicate Player",
+            NoticeCode::Banned => "ERR:Banned",
+            NoticeCode::ResumeFailed => "ERR:Resume failed",
+            NoticeCode::PasswordRequired => "ERR:Password required",
+            NoticeCode::WrongPassword => "ERR:Wrong password",
+            NoticeCode::MigrationFailed => "ERR:Host migration failed",
+            NoticeCode::SpectatorLimit => "ERR:Spectator limit reached",
+            NoticeCode::ConnectionLimit => "ERR:Connection limit reached",
+            NoticeCode::UnaddressableClients => "ERR:Room has clients a v1 server cannot address",
+            NoticeCode::PayloadTooLarge => "ERR:Payload too large",
+            NoticeCode::RateLimited => "WARN:Rate limited",
+            NoticeCode::RateLimitKicked => "ERR:Rate limited",
+            NoticeCode::Kicked => "INFO:Kicked",
+            NoticeCode::ExcludeTooLarge => "ERR:Exclude list too large",
+            NoticeCode::HostMigrating => "INFO:Host migrating",
+            NoticeCode::HostMigrated => "INFO:Host migrated",
+            NoticeCode::MessageTooLarge => "ERR:Message too large",
+            NoticeCode::FragmentTimeout => "WARN:Fragment timeout",
+            NoticeCode::InvalidFragment => "ERR:Invalid fragment",
+            NoticeCode::SpectatorReadOnly => "ERR:Spectators cannot send",
+            NoticeCode::Denied => "ERR:Denied",
+            NoticeCode::RelayShutdown => "INFO:Relay shutting down",
+            NoticeCode::InvalidAction => "Invalid action packet",
+            NoticeCode::UnknownAction => "Unknown action type",
+            NoticeCode::MissingSessionId => "Session id cannot be empty",
+            // v1 回退文本沿用旧版, 前缀长度错误同样以此提示
+            NoticeCode::InvalidIp => "[Ban] Ipv4 syntax error",
+            NoticeCode::Unbanned => "INFO:Unban",
+            NoticeCode::NotBanned => "INFO:This ip is not banned",
+            NoticeCode::InvalidQueryFlags => "[Query] Invalid flags",
+            NoticeCode::InvalidGroup => "[Group] Invalid group packet",
+            NoticeCode::GroupLimit => "[Group] Group limit reached",
+            NoticeCode::SessionNotFound => "Unknown session id",
+            NoticeCode::NotPending => "[Deny] Client is not waiting for permit",
+            NoticeCode::InvalidReason => "Reason must be UTF-8",
+        }
+    }
+}
+
+/// 通知码与其参数
+#[derive(Debug, Clone)]
+pub struct Notice {
+    pub code: NoticeCode,
+    pub args: Vec<String>,
+}
+
+impl Notice {
+    pub fn with_arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// 发给 v1 对端的 RelayMessage 文本
+    pub fn fallback(&self) -> String {
+        let text = self.code.text();
+        match (self.code, self.args.as_slice()) {
+            (NoticeCode::MissingSessionId, [action, ..]) => format!("[{}] {}", action, text),
+            (_, []) => text.to_string(),
+            (_, args) => format!("{}: {}", text, args.join(", ")),
+        }
+    }
+}
+
+impl From<NoticeCode> for Notice {
+    fn from(code: NoticeCode) -> Self {
+        Notice {
+            code,
+            args: Vec::new(),
+        }
+    }
+}
@@ -1,4 +1,10 @@
+use crate::network::ban::IpRange;
+use crate::network::header::{QUERY_ADDRESS, QUERY_QUEUE, QUERY_RTT, QUERY_STATE, QUERY_TIMING};
+use crate::network::notice::Notice;
+use crate::network::stats::{RttStats, SessionTraffic};
+use crate::network::version::ProtocolVersion;
 use bytes::{BufMut, Bytes, BytesMut};
+use std::net::{IpAddr, SocketAddr};
 
 pub trait Payload {
     const PAYLOAD_TYPE: u8;
@@ -7,45 +13,56 @@ pub trait Payload {
     }
 }
 
+/// 含 session id 的负载按接收方的协议版本编码:
+/// v1 为 u8, v2 为 u16 LE
 pub struct Detached {
-    pub session_id: u8,
+    pub session_id: u16,
+    pub version: ProtocolVersion,
 }
 impl Payload for Detached {
     const PAYLOAD_TYPE: u8 = 0x00;
     fn to_bytes(&self) -> Bytes {
-        let buf: [u8; 3] = [0x00, Self::PAYLOAD_TYPE, self.session_id];
-        Bytes::copy_from_slice(&buf)
+        let mut buf = BytesMut::with_capacity(4);
+        buf.put_u8(0x00);
+        buf.put_u8(Self::PAYLOAD_TYPE);
+        self.version.put_id(&mut buf, self.session_id);
+        buf.freeze()
     }
 }
 
 pub struct Attached {
-    pub session_id: u8,
+    pub session_id: u16,
+    pub version: ProtocolVersion,
 }
 impl Payload for Attached {
     const PAYLOAD_TYPE: u8 = 0x01;
 
     fn to_bytes(&self) -> Bytes {
-        let buf: [u8; 3] = [0x00, Self::PAYLOAD_TYPE, self.session_id];
-        Bytes::copy_from_slice(&buf)
+        let mut buf = BytesMut::with_capacity(4);
+        buf.put_u8(0x00);
+        buf.put_u8(Self::PAYLOAD_TYPE);
+        self.version.put_id(&mut buf, self.session_id);
+        buf.freeze()
     }
 }
 
 /// 区别于 Attached,
 /// 这是给服务端的通知
 pub struct ClientAttached {
-    pub session_id: u8,
+    pub session_id: u16,
     pub uuid: [u8; 16],
+    pub version: ProtocolVersion,
 }
 impl Payload for ClientAttached {
     const PAYLOAD_TYPE: u8 = 0x02;
 
     fn to_bytes(&self) -> Bytes {
-        let mut buf: [u8; 19] = [0; 19];
-        buf[0] = 0x00;
-        buf[1] = Self::PAYLOAD_TYPE;
-        buf[2] = self.session_id;
-        buf[3..].copy_from_slice(&self.uuid);
-        Bytes::copy_from_slice(&buf)
+        let mut buf = BytesMut::with_capacity(20);
+        buf.put_u8(0x00);
+        buf.put_u8(Self::PAYLOAD_TYPE);
+        self.version.put_id(&mut buf, self.session_id);
+        buf.put_slice(&self.uuid);
+        buf.freeze()
     }
 }
 
@@ -75,26 +92,432 @@ impl Payload for RelayMessage {
     }
 }
 
+/// 结构化的中继通知, 仅发给 v2 对端; v1 对端收到同义的 RelayMessage 文本
+/// 格式: [0x00][0x0A][level u8][code u16 LE][argc u8]([len u16 LE][utf8])*
+pub struct RelayNotice {
+    pub notice: Notice,
+}
+impl Payload for RelayNotice {
+    const PAYLOAD_TYPE: u8 = 0x0A;
+
+    fn to_bytes(&self) -> Bytes {
+        let notice = &self.notice;
+        let args = &notice.args[..notice.args.len().min(u8::MAX as usize)];
+        let args_len: usize = args.iter().map(|a| 2 + a.len()).sum();
+
+        let mut buf = BytesMut::with_capacity(6 + args_len);
+        buf.put_u8(0x00);
+        buf.put_u8(Self::PAYLOAD_TYPE);
+        buf.put_u8(notice.code.level() as u8);
+        buf.put_u16_le(notice.code as u16);
+        buf.put_u8(args.len() as u8);
+        for arg in args {
+            let bytes = arg.as_bytes();
+            let bytes = &bytes[..bytes.len().min(u16::MAX as usize)];
+            buf.put_u16_le(bytes.len() as u16);
+            buf.put_slice(bytes);
+        }
+        buf.freeze()
+    }
+}
+
+/// 房间创建成功, 先于 Attached 发给创建者
+/// 格式: [0x00][0x05][room_code u32 LE]
+pub struct RoomCreated {
+    pub room_code: u32,
+}
+impl Payload for RoomCreated {
+    const PAYLOAD_TYPE: u8 = 0x05;
+
+    fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(6);
+        buf.put_u8(0x00);
+        buf.put_u8(Self::PAYLOAD_TYPE);
+        buf.put_u32_le(self.room_code);
+        buf.freeze()
+    }
+}
+
 /// Server 查询当前在线客户端列表的回包
-/// 格式: [0x00][0x04][count u8]([session_id u8][uuid 16B])*
+/// v1 格式: [0x00][0x04][count u8]([session_id u8][uuid 16B])*
+/// v2 格式: [0x00][0x04][count u16]([session_id u16][uuid 16B])*
 pub struct QueryClientsResult {
     /// (session_id, uuid) 对列表
-    pub clients: Vec<(u8, [u8; 16])>,
+    pub clients: Vec<(u16, [u8; 16])>,
+    pub version: ProtocolVersion,
 }
 impl Payload for QueryClientsResult {
     const PAYLOAD_TYPE: u8 = 0x04;
 
     fn to_bytes(&self) -> Bytes {
-        let count = self.clients.len().min(u8::MAX as usize);
-        // header(2) + count(1) + count * (session_id(1) + uuid(16))
-        let mut buf = BytesMut::with_capacity(3 + count * 17);
+        encode_client_list(Self::PAYLOAD_TYPE, &self.clients, self.version)
+    }
+}
+
+/// [0x00][type][count u8|u16]([session_id u8|u16][uuid 16B])*
+fn encode_client_list(
+    payload_type: u8,
+    clients: &[(u16, [u8; 16])],
+    version: ProtocolVersion,
+) -> Bytes {
+    let max_count = match version {
+        ProtocolVersion::V1 => u8::MAX as usize,
+        ProtocolVersion::V2 => u16::MAX as usize,
+    };
+    // v1 无法表示的 id 不返回
+    let clients: Vec<&(u16, [u8; 16])> = clients
+        .iter()
+        .filter(|(sid, _)| version.can_encode(*sid))
+        .take(max_count)
+        .collect();
+
+    let id_len = version.id_len();
+    let mut buf = BytesMut::with_capacity(2 + id_len + clients.len() * (id_len + 16));
+    buf.put_u8(0x00);
+    buf.put_u8(payload_type);
+    match version {
+        ProtocolVersion::V1 => buf.put_u8(clients.len() as u8),
+        ProtocolVersion::V2 => buf.put_u16_le(clients.len() as u16),
+    }
+    for (sid, uuid) in clients {
+        version.put_id(&mut buf, *sid);
+        buf.put_slice(uuid.as_ref());
+    }
+    buf.freeze()
+}
+
+/// 会话恢复凭证, 仅在 v2 客户端的 Attached 之后下发
+/// 格式: [0x00][0x06][token 16B]
+pub struct ResumeToken {
+    pub token: [u8; 16],
+}
+impl Payload for ResumeToken {
+    const PAYLOAD_TYPE: u8 = 0x06;
+
+    fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(18);
+        buf.put_u8(0x00);
+        buf.put_u8(Self::PAYLOAD_TYPE);
+        buf.put_slice(&self.token);
+        buf.freeze()
+    }
+}
+
+/// 入房密码挑战, 对端须以带应答的注册包重新注册, 见 auth::PasswordHash
+/// 格式: [0x00][0x10][salt 16B][nonce 16B]
+pub struct AuthChallenge {
+    pub salt: [u8; 16],
+    pub nonce: [u8; 16],
+}
+impl Payload for AuthChallenge {
+    const PAYLOAD_TYPE: u8 = 0x10;
+
+    fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(34);
+        buf.put_u8(0x00);
+        buf.put_u8(Self::PAYLOAD_TYPE);
+        buf.put_slice(&self.salt);
+        buf.put_slice(&self.nonce);
+        buf.freeze()
+    }
+}
+
+/// 扩展查询中的单个客户端
+pub struct ClientDetails {
+    pub session_id: u16,
+    pub uuid: [u8; 16],
+    pub rtt: Option<RttStats>,
+    /// 仍在等待服务端放行
+    pub pending: bool,
+    pub address: Option<SocketAddr>,
+    /// 建立连接时的毫秒时间戳
+    pub connected_at: u64,
+    /// 距最后一次收到该客户端帧的毫秒数
+    pub idle_ms: u64,
+    /// 发送管道中尚未写出的帧数
+    pub queue_depth: usize,
+}
+
+/// QueryClients 带标志位时的回包, 每项按标志位依次追加字段
+/// 格式: [0x00][0x09][flags u8][count u8|u16]([session_id u8|u16][uuid 16B][fields])*
+/// QUERY_RTT: [latest u16][smoothed u16][jitter u16], 单位毫秒, 未测得时为 0xFFFF
+/// QUERY_STATE: [state u8], 0 已放行, 1 等待放行
+/// QUERY_ADDRESS: [family 4|6][addr 4|16B][port u16], 地址为网络字节序, 未知时仅 [0]
+/// QUERY_TIMING: [connected_at u64][idle u32], 单位毫秒
+/// QUERY_QUEUE: [depth u16]
+pub struct QueryClientsExtResult {
+    pub flags: u8,
+    pub clients: Vec<ClientDetails>,
+    pub version: ProtocolVersion,
+}
+impl QueryClientsExtResult {
+    /// 回包中实际包含的标志位, 未知标志位被忽略
+    pub const SUPPORTED_FLAGS: u8 =
+        QUERY_RTT | QUERY_STATE | QUERY_ADDRESS | QUERY_TIMING | QUERY_QUEUE;
+}
+impl Payload for QueryClientsExtResult {
+    const PAYLOAD_TYPE: u8 = 0x09;
+
+    fn to_bytes(&self) -> Bytes {
+        let version = self.version;
+        let flags = self.flags & Self::SUPPORTED_FLAGS;
+        let max_count = match version {
+            ProtocolVersion::V1 => u8::MAX as usize,
+            ProtocolVersion::V2 => u16::MAX as usize,
+        };
+        let clients: Vec<&ClientDetails> = self
+            .clients
+            .iter()
+            .filter(|c| version.can_encode(c.session_id))
+            .take(max_count)
+            .collect();
+
+        let id_len = version.id_len();
+        let mut buf = BytesMut::with_capacity(3 + id_len + clients.len() * (id_len + 22));
         buf.put_u8(0x00);
         buf.put_u8(Self::PAYLOAD_TYPE);
-        buf.put_u8(count as u8);
-        for (sid, uuid) in self.clients.iter().take(count) {
-            buf.put_u8(*sid);
-            buf.put_slice(uuid.as_ref());
+        buf.put_u8(flags);
+        match version {
+            ProtocolVersion::V1 => buf.put_u8(clients.len() as u8),
+            ProtocolVersion::V2 => buf.put_u16_le(clients.len() as u16),
         }
+        for client in clients {
+            version.put_id(&mut buf, client.session_id);
+            buf.put_slice(&client.uuid);
+            if flags & QUERY_RTT != 0 {
+                let ms = |v: f64| v.round().min(u16::MAX as f64) as u16;
+                match client.rtt {
+                    Some(rtt) => {
+                        buf.put_u16_le(ms(rtt.latest_ms));
+                        buf.put_u16_le(ms(rtt.smoothed_ms));
+                        buf.put_u16_le(ms(rtt.jitter_ms));
+                    }
+                    None => {
+                        buf.put_u16_le(u16::MAX);
+                        buf.put_u16_le(u16::MAX);
+                        buf.put_u16_le(u16::MAX);
+                    }
+                }
+            }
+            if flags & QUERY_STATE != 0 {
+                buf.put_u8(client.pending as u8);
+            }
+            if flags & QUERY_ADDRESS != 0 {
+                match client.address {
+                    Some(SocketAddr::V4(addr)) => {
+                        buf.put_u8(4);
+                        buf.put_slice(&addr.ip().octets());
+                        buf.put_u16_le(addr.port());
+                    }
+                    Some(SocketAddr::V6(addr)) => {
+                        buf.put_u8(6);
+                        buf.put_slice(&addr.ip().octets());
+                        buf.put_u16_le(addr.port());
+                    }
+                    None => buf.put_u8(0),
+                }
+            }
+            if flags & QUERY_TIMING != 0 {
+                buf.put_u64_le(client.connected_at);
+                buf.put_u32_le(client.idle_ms.min(u32::MAX as u64) as u32);
+            }
+            if flags & QUERY_QUEUE != 0 {
+                buf.put_u16_le(client.queue_depth.min(u16::MAX as usize) as u16);
+            }
+        }
+        buf.freeze()
+    }
+}
+
+/// Server 查询客户端限流计数的回包
+/// v1 格式: [0x00][0x07][count u8]([session_id u8][frames u64][bytes u64])*
+/// v2 格式: [0x00][0x07][count u16]([session_id u16][frames u64][bytes u64])*
+pub struct RateLimitStats {
+    /// (session_id, 超额帧数, 超额字节数) 列表
+    pub clients: Vec<(u16, u64, u64)>,
+    pub version: ProtocolVersion,
+}
+impl Payload for RateLimitStats {
+    const PAYLOAD_TYPE: u8 = 0x07;
+
+    fn to_bytes(&self) -> Bytes {
+        let version = self.version;
+        let max_count = match version {
+            ProtocolVersion::V1 => u8::MAX as usize,
+            ProtocolVersion::V2 => u16::MAX as usize,
+        };
+        let clients: Vec<&(u16, u64, u64)> = self
+            .clients
+            .iter()
+            .filter(|(sid, _, _)| version.can_encode(*sid))
+            .take(max_count)
+            .collect();
+
+        let id_len = version.id_len();
+        let mut buf = BytesMut::with_capacity(2 + id_len + clients.len() * (id_len + 16));
+        buf.put_u8(0x00);
+        buf.put_u8(Self::PAYLOAD_TYPE);
+        match version {
+            ProtocolVersion::V1 => buf.put_u8(clients.len() as u8),
+            ProtocolVersion::V2 => buf.put_u16_le(clients.len() as u16),
+        }
+        for (sid, frames, bytes) in clients {
+            version.put_id(&mut buf, *sid);
+            buf.put_u64_le(*frames);
+            buf.put_u64_le(*bytes);
+        }
+        buf.freeze()
+    }
+}
+
+/// Server 查询房间流量的回包, 首项为服务端自身
+/// 格式: [0x00][0x08][count u8|u16]([session_id u8|u16][frames_in u64][bytes_in u64]
+///       [frames_out u64][bytes_out u64][dropped u64][connected_at u64][last_active u64])*
+pub struct TrafficStatsResult {
+    pub sessions: Vec<SessionTraffic>,
+    pub version: ProtocolVersion,
+}
+impl Payload for TrafficStatsResult {
+    const PAYLOAD_TYPE: u8 = 0x08;
+
+    fn to_bytes(&self) -> Bytes {
+        let version = self.version;
+        let max_count = match version {
+            ProtocolVersion::V1 => u8::MAX as usize,
+            ProtocolVersion::V2 => u16::MAX as usize,
+        };
+        let sessions: Vec<&SessionTraffic> = self
+            .sessions
+            .iter()
+            .filter(|s| version.can_encode(s.session_id))
+            .take(max_count)
+            .collect();
+
+        let id_len = version.id_len();
+        let mut buf = BytesMut::with_capacity(2 + id_len + sessions.len() * (id_len + 56));
+        buf.put_u8(0x00);
+        buf.put_u8(Self::PAYLOAD_TYPE);
+        match version {
+            ProtocolVersion::V1 => buf.put_u8(sessions.len() as u8),
+            ProtocolVersion::V2 => buf.put_u16_le(sessions.len() as u16),
+        }
+        for session in sessions {
+            let traffic = &session.traffic;
+            version.put_id(&mut buf, session.session_id);
+            buf.put_u64_le(traffic.frames_in);
+            buf.put_u64_le(traffic.bytes_in);
+            buf.put_u64_le(traffic.frames_out);
+            buf.put_u64_le(traffic.bytes_out);
+            buf.put_u64_le(traffic.dropped);
+            buf.put_u64_le(session.connected_at);
+            buf.put_u64_le(session.last_active);
+        }
+        buf.freeze()
+    }
+}
+
+/// Server 查询封禁列表的回包, 地址为网络字节序
+/// 格式: [0x00][0x0B][count u16]([family 4|6][addr 4|16B][prefix u8])*
+pub struct BanList {
+    pub bans: Vec<IpRange>,
+}
+impl Payload for BanList {
+    const PAYLOAD_TYPE: u8 = 0x0B;
+
+    fn to_bytes(&self) -> Bytes {
+        let bans = &self.bans[..self.bans.len().min(u16::MAX as usize)];
+        let mut buf = BytesMut::with_capacity(4 + bans.len() * 18);
+        buf.put_u8(0x00);
+        buf.put_u8(Self::PAYLOAD_TYPE);
+        buf.put_u16_le(bans.len() as u16);
+        for range in bans {
+            match range.addr() {
+                IpAddr::V4(v4) => {
+                    buf.put_u8(4);
+                    buf.put_slice(&v4.octets());
+                }
+                IpAddr::V6(v6) => {
+                    buf.put_u8(6);
+                    buf.put_slice(&v6.octets());
+                }
+            }
+            buf.put_u8(range.prefix());
+        }
+        buf.freeze()
+    }
+}
+
+/// 接管迁移中房间的新服务端收到的存活客户端列表, 紧随 Attached 发送
+/// 格式同 QueryClientsResult: [0x00][0x0C][count u8|u16]([session_id u8|u16][uuid 16B])*
+pub struct HostMigrated {
+    pub clients: Vec<(u16, [u8; 16])>,
+    pub version: ProtocolVersion,
+}
+impl Payload for HostMigrated {
+    const PAYLOAD_TYPE: u8 = 0x0C;
+
+    fn to_bytes(&self) -> Bytes {
+        encode_client_list(Self::PAYLOAD_TYPE, &self.clients, self.version)
+    }
+}
+
+/// 房主迁移凭证, 仅在开启迁移时下发给 v2 服务端.
+/// 服务端可将其转交给候选房主, 由其以 REG_MIGRATE 接管房间
+/// 格式: [0x00][0x0D][token 16B]
+pub struct MigrationToken {
+    pub token: [u8; 16],
+}
+impl Payload for MigrationToken {
+    const PAYLOAD_TYPE: u8 = 0x0D;
+
+    fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(18);
+        buf.put_u8(0x00);
+        buf.put_u8(Self::PAYLOAD_TYPE);
+        buf.put_slice(&self.token);
+        buf.freeze()
+    }
+}
+
+/// 客户端发送队列越过高/低水位, 仅下发给 v2 服务端, 服务端据此调整对该客户端的发送频率
+/// 格式: [0x00][0x0E][session_id u16][congested u8][depth u16][dropped u32]
+/// dropped 为自上次通知以来丢弃的帧数
+pub struct Backpressure {
+    pub session_id: u16,
+    pub congested: bool,
+    pub depth: u16,
+    pub dropped: u32,
+}
+impl Payload for Backpressure {
+    const PAYLOAD_TYPE: u8 = 0x0E;
+
+    fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(11);
+        buf.put_u8(0x00);
+        buf.put_u8(Self::PAYLOAD_TYPE);
+        buf.put_u16_le(self.session_id);
+        buf.put_u8(self.congested as u8);
+        buf.put_u16_le(self.depth);
+        buf.put_u32_le(self.dropped);
+        buf.freeze()
+    }
+}
+
+/// 旁观者接入, 仅下发给 v2 服务端. 旁观者离开时同样下发 Detached
+/// 格式: [0x00][0x0F][session_id u16][uuid 16B]
+pub struct SpectatorAttached {
+    pub session_id: u16,
+    pub uuid: [u8; 16],
+}
+impl Payload for SpectatorAttached {
+    const PAYLOAD_TYPE: u8 = 0x0F;
+
+    fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(20);
+        buf.put_u8(0x00);
+        buf.put_u8(Self::PAYLOAD_TYPE);
+        buf.put_u16_le(self.session_id);
+        buf.put_slice(&self.uuid);
         buf.freeze()
     }
 }
@@ -0,0 +1,316 @@
+use crate::network::session::Session;
+use crate::network::states::Role;
+use crate::network::util::now_ms;
+use crate::network::version::ProtocolVersion;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::{error, info, warn};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+/// 录制文件格式, 整数均为小端:
+/// 文件头: ["NREC"][format u8][started_at u64 毫秒时间戳]
+/// 记录: [kind u8][elapsed_ms u32][room u32][session_id u16][len u32][data]
+pub const MAGIC: &[u8; 4] = b"NREC";
+pub const FORMAT_VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 13;
+pub const RECORD_HEADER_LEN: usize = 15;
+
+pub const DEFAULT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// 写盘线程来不及处理时丢弃记录, 不阻塞中继
+const QUEUE_LEN: usize = 4096;
+
+/// 记录类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RecordKind {
+    /// 客户端发来的帧
+    ClientFrame = 0,
+    /// 服务端发来的帧
+    ServerFrame = 1,
+    /// 会话接入, data: [role u8 0=Server 1=Client 2=Spectator][version u8][uuid 16B 仅客户端与旁观者]
+    Attach = 2,
+    /// 会话断开, data 为空
+    Detach = 3,
+}
+
+impl RecordKind {
+    pub fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(RecordKind::ClientFrame),
+            1 => Some(RecordKind::ServerFrame),
+            2 => Some(RecordKind::Attach),
+            3 => Some(RecordKind::Detach),
+            _ => None,
+        }
+    }
+}
+
+/// 接入记录携带的会话信息
+#[derive(Debug, Clone, Copy)]
+pub struct AttachInfo {
+    pub is_server: bool,
+    pub is_spectator: bool,
+    pub version: ProtocolVersion,
+    pub uuid: Option<[u8; 16]>,
+}
+
+impl AttachInfo {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let (&role, rest) = data.split_first()?;
+        let (&version, rest) = rest.split_first()?;
+        let version = ProtocolVersion::from_number(version)?;
+        let uuid = match (role, rest.len()) {
+            (0, 0) => None,
+            (1 | 2, 16) => rest.try_into().ok(),
+            _ => return None,
+        };
+        Some(AttachInfo {
+            is_server: role == 0,
+            is_spectator: role == 2,
+            version,
+            uuid,
+        })
+    }
+}
+
+/// 录制文件中的一条记录
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub kind: RecordKind,
+    /// 相对录制开始的毫秒数
+    pub elapsed_ms: u32,
+    pub room: u32,
+    pub session_id: u16,
+    pub data: Vec<u8>,
+}
+
+pub struct Recording {
+    /// 录制开始的毫秒时间戳
+    pub started_at: u64,
+    pub records: Vec<Record>,
+}
+
+/// 读取录制文件, 录制中断留下的不完整尾记录会被忽略
+pub fn read_recording(path: &Path) -> Result<Recording, String> {
+    let data =
+        std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    if data.len() < HEADER_LEN || &data[..4] != MAGIC {
+        return Err(format!("{} is not a relay recording", path.display()));
+    }
+    if data[4] != FORMAT_VERSION {
+        return Err(format!("Unsupported recording format {}", data[4]));
+    }
+
+    let mut cursor = &data[5..];
+    let started_at = cursor.get_u64_le();
+    let mut records = Vec::new();
+    while cursor.len() >= RECORD_HEADER_LEN {
+        let kind = RecordKind::from_u8(cursor[0])
+            .ok_or_else(|| format!("Unknown record kind {}", cursor[0]))?;
+        let len = u32::from_le_bytes([cursor[11], cursor[12], cursor[13], cursor[14]]) as usize;
+        if cursor.len() < RECORD_HEADER_LEN + len {
+            break;
+        }
+
+        cursor.advance(1);
+        let elapsed_ms = cursor.get_u32_le();
+        let room = cursor.get_u32_le();
+        let session_id = cursor.get_u16_le();
+        cursor.advance(4);
+        records.push(Record {
+            kind,
+            elapsed_ms,
+            room,
+            session_id,
+            data: cursor[..len].to_vec(),
+        });
+        cursor.advance(len);
+    }
+
+    Ok(Recording {
+        started_at,
+        records,
+    })
+}
+
+/// 录制结果, 停止录制时返回
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordingSummary {
+    pub path: PathBuf,
+    pub bytes: u64,
+    /// 队列已满而丢弃的记录数
+    pub dropped: u64,
+    /// 是否因达到大小上限而提前停止
+    pub truncated: bool,
+}
+
+pub(crate) struct Recorder {
+    path: PathBuf,
+    tx: mpsc::Sender<Bytes>,
+    started: Instant,
+    written: Arc<AtomicU64>,
+    truncated: Arc<AtomicBool>,
+    /// 写盘线程已退出, 之后的记录直接忽略
+    stopped: Arc<AtomicBool>,
+    dropped: AtomicU64,
+    done: oneshot::Receiver<()>,
+}
+
+impl Recorder {
+    /// 创建录制文件并启动写盘线程, 文件总大小不超过 max_bytes
+    pub fn create(path: &Path, max_bytes: u64) -> Result<Self, String> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        let mut writer = BufWriter::new(file);
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(FORMAT_VERSION);
+        header.extend_from_slice(&(now_ms() as u64).to_le_bytes());
+        writer
+            .write_all(&header)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+        let (tx, rx) = mpsc::channel::<Bytes>(QUEUE_LEN);
+        let (done_tx, done_rx) = oneshot::channel::<()>();
+        let written = Arc::new(AtomicU64::new(HEADER_LEN as u64));
+        let truncated = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let task = WriteTask {
+            writer,
+            rx,
+            written: written.clone(),
+            truncated: truncated.clone(),
+            stopped: stopped.clone(),
+            max_bytes,
+        };
+        std::thread::Builder::new()
+            .name("relay-recorder".into())
+            .spawn(move || {
+                task.run();
+                let _ = done_tx.send(());
+            })
+            .map_err(|e| format!("Failed to start recorder: {}", e))?;
+
+        info!("Recording relay traffic to {}", path.display());
+        Ok(Recorder {
+            path: path.to_path_buf(),
+            tx,
+            started: Instant::now(),
+            written,
+            truncated,
+            stopped,
+            dropped: AtomicU64::new(0),
+            done: done_rx,
+        })
+    }
+
+    /// 达到大小上限或写盘出错后为 true, 仍需 finish 取回录制结果
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    pub fn record(&self, kind: RecordKind, room: u32, session_id: u16, data: &[u8]) {
+        if self.is_stopped() {
+            return;
+        }
+        let elapsed = self.started.elapsed().as_millis().min(u32::MAX as u128) as u32;
+        let mut buf = BytesMut::with_capacity(RECORD_HEADER_LEN + data.len());
+        buf.put_u8(kind as u8);
+        buf.put_u32_le(elapsed);
+        buf.put_u32_le(room);
+        buf.put_u16_le(session_id);
+        buf.put_u32_le(data.len() as u32);
+        buf.put_slice(data);
+
+        if self.tx.try_send(buf.freeze()).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_attach(&self, room: u32, session: &Session) {
+        let mut data = Vec::with_capacity(18);
+        data.push(match session.role {
+            Role::Server => 0,
+            Role::Client => 1,
+            Role::Spectator => 2,
+        });
+        data.push(session.version.number());
+        if let Some(uuid) = session.uuid {
+            data.extend_from_slice(&uuid);
+        }
+        self.record(RecordKind::Attach, room, session.session_id, &data);
+    }
+
+    /// 关闭队列并等待写盘线程落盘
+    pub async fn finish(self) -> RecordingSummary {
+        let Recorder {
+            path,
+            tx,
+            written,
+            truncated,
+            dropped,
+            done,
+            ..
+        } = self;
+        drop(tx);
+        let _ = done.await;
+
+        info!("Recording saved to {}", path.display());
+        RecordingSummary {
+            path,
+            bytes: written.load(Ordering::Relaxed),
+            dropped: dropped.load(Ordering::Relaxed),
+            truncated: truncated.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct WriteTask {
+    writer: BufWriter<File>,
+    rx: mpsc::Receiver<Bytes>,
+    written: Arc<AtomicU64>,
+    truncated: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    max_bytes: u64,
+}
+
+impl WriteTask {
+    fn run(mut self) {
+        while let Some(record) = self.rx.blocking_recv() {
+            let total = self.written.load(Ordering::Relaxed) + record.len() as u64;
+            if total > self.max_bytes {
+                warn!(
+                    "Recording reached its size limit of {} bytes",
+                    self.max_bytes
+                );
+                self.truncated.store(true, Ordering::Relaxed);
+                break;
+            }
+            if let Err(e) = self.writer.write_all(&record) {
+                error!("Failed to write recording: {}", e);
+                break;
+            }
+            self.written.store(total, Ordering::Relaxed);
+        }
+        self.stopped.store(true, Ordering::Relaxed);
+        // 释放队列中未写出的记录
+        drop(self.rx);
+
+        if let Err(e) = self.writer.flush() {
+            error!("Failed to flush recording: {}", e);
+        }
+    }
+}
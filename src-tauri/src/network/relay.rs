@@ -0,0 +1,165 @@
+use crate::network::auth::PasswordHash;
+use crate::network::ban::load_bans;
+use crate::network::config::RelayConfig;
+use crate::network::recorder::RecordingSummary;
+use crate::network::states::{RelayState, ServerHandle, ServerManager, DEFAULT_ROOM};
+use crate::network::stats::RelayStats;
+use crate::network::tls::TlsIdentity;
+use crate::network::wss::{run_ws_server, OPEN_FLAG, SERVER_MANAGER};
+use log::{error, info};
+use rand::RngCore;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+
+/// 启动中继, 桌面端与独立中继共用.
+/// 未提供密钥时随机生成, 返回实际使用的密钥与中继任务.
+/// 提供 TLS 证书时以 wss:// 提供服务, 提供非空密码时客户端须携带密码注册
+pub async fn start_relay(
+    config: RelayConfig,
+    secret: Option<[u8; 32]>,
+    tls: Option<&TlsIdentity>,
+    join_password: Option<&str>,
+) -> Result<([u8; 32], JoinHandle<()>), String> {
+    config.validate()?;
+    let bans = match config.ban_file.as_ref() {
+        Some(path) => load_bans(path)?,
+        None => Vec::new(),
+    };
+    let acceptor = tls.map(|identity| identity.acceptor()).transpose()?;
+
+    let state_cell = SERVER_MANAGER
+        .get_or_init(|| async {
+            Mutex::new(ServerManager {
+                handle: None,
+                stop_tx: None,
+            })
+        })
+        .await;
+
+    let mut guard = state_cell.lock().await;
+    if let Some(handle) = guard.handle.as_ref() {
+        return Err(format!("Server already listen on \"{}\"", handle.port));
+    }
+
+    // 开始监听
+    let addr = SocketAddr::new(config.bind_addr, config.port);
+    let listener = match TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Failed to bind {}: {}", addr, e);
+            return Err("Port already in use".into());
+        }
+    };
+    let addr = listener.local_addr().unwrap_or(addr);
+
+    let scheme = if acceptor.is_some() { "wss" } else { "ws" };
+    info!("WebSocket server listening on {}://{}", scheme, addr);
+
+    let (tx, rx) = oneshot::channel::<oneshot::Sender<()>>();
+    guard.stop_tx = Some(tx);
+
+    // 生成随机密钥
+    let secret = secret.unwrap_or_else(|| {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        secret
+    });
+
+    let port = addr.port();
+    let join_password = PasswordHash::from_option(join_password);
+    let state = Arc::new(RelayState::new(config, secret, bans, join_password));
+    let task = tokio::spawn(run_ws_server(listener, state.clone(), acceptor, rx));
+
+    guard.handle = Some(ServerHandle { port, state });
+
+    Ok((secret, task))
+}
+
+/// 通知所有会话并等待连接收尾, 返回时端口已释放, 可立即重新启动
+pub async fn stop_relay() -> bool {
+    let Some(state_cell) = SERVER_MANAGER.get() else {
+        info!("Server not initialized");
+        return false;
+    };
+
+    // 中继任务收尾时需要此锁, 等待前先释放
+    let Some(tx) = state_cell.lock().await.stop_tx.take() else {
+        info!("Server not running");
+        return false;
+    };
+    let (done_tx, done_rx) = oneshot::channel::<()>();
+    info!("Stopping server");
+    if tx.send(done_tx).is_ok() {
+        let _ = done_rx.await;
+    }
+    true
+}
+
+/// 修改运行中中继默认房间的入房密码, 空密码表示取消.
+/// 仅影响之后的注册, 已连接的客户端不受影响
+pub async fn set_join_password(password: Option<&str>) -> bool {
+    let Some(room) = running_state().await.and_then(|s| s.room(DEFAULT_ROOM)) else {
+        return false;
+    };
+
+    let password = PasswordHash::from_option(password);
+    info!(
+        "Join password {}",
+        if password.is_some() { "set" } else { "cleared" }
+    );
+    room.set_join_password(password);
+    true
+}
+
+/// 开始将经过中继的帧录制到文件, 文件大小不超过 max_bytes
+pub async fn start_recording(path: &Path, max_bytes: u64) -> Result<(), String> {
+    running_state()
+        .await
+        .ok_or("Server not running")?
+        .start_recording(path, max_bytes)
+}
+
+/// 停止录制并等待落盘, 未在录制时返回 None
+pub async fn stop_recording() -> Option<RecordingSummary> {
+    let recorder = running_state().await?.take_recorder()?;
+    Some(recorder.finish().await)
+}
+
+async fn running_state() -> Option<Arc<RelayState>> {
+    let guard = SERVER_MANAGER.get()?.lock().await;
+    Some(guard.handle.as_ref()?.state.clone())
+}
+
+/// 运行中中继实际监听的端口
+pub async fn local_port() -> Option<u16> {
+    let guard = SERVER_MANAGER.get()?.lock().await;
+    Some(guard.handle.as_ref()?.port)
+}
+
+/// 运行中中继的流量统计
+pub async fn stats() -> Option<RelayStats> {
+    let state = running_state().await?;
+    Some(state.stats().await)
+}
+
+pub fn set_open(bl: bool) -> bool {
+    if let Some(flag) = OPEN_FLAG.get() {
+        flag.store(bl, Ordering::SeqCst);
+        true
+    } else {
+        false
+    }
+}
+
+pub fn is_open() -> bool {
+    if let Some(flag) = OPEN_FLAG.get() {
+        return flag.load(Ordering::SeqCst);
+    }
+
+    false
+}
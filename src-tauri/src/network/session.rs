@@ -1,7 +1,14 @@
-use crate::network::states::{Role, Tx};
-use std::collections::VecDeque;
+use crate::network::delivery::{LatestSlots, Outgoing};
+use crate::network::header::CAP_COMPRESSION;
+use crate::network::limit::Limiter;
+use crate::network::states::{Role, Room, Tx};
+use crate::network::stats::SessionStats;
+use crate::network::version::ProtocolVersion;
+use bytes::Bytes;
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
 use std::sync::{Arc, LazyLock};
-use tokio::sync::{oneshot, Mutex};
+use tokio::sync::{mpsc, oneshot, Mutex};
 
 pub static NEXT_SESSION_ID: LazyLock<SessionAllocator> = LazyLock::new(|| SessionAllocator::new());
 
@@ -9,25 +16,61 @@ pub static NEXT_SESSION_ID: LazyLock<SessionAllocator> = LazyLock::new(|| Sessio
 pub struct Session {
     pub tx: Tx,
     pub role: Role,
-    pub session_id: u8,
+    pub session_id: u16,
     pub uuid: Option<[u8; 16]>,
+    pub version: ProtocolVersion,
+    /// 注册时的对端地址, 服务端为 None
+    pub address: Option<SocketAddr>,
+    /// 客户端 C2S 限流, 服务端不限
+    pub limiter: Option<Limiter>,
+    pub stats: Arc<SessionStats>,
+    /// 客户端在注册时声明可接收压缩帧
+    pub compression: bool,
+    /// 按键合并, 尚未写出的帧
+    pub latest: LatestSlots,
+}
+
+/// 客户端注册包中的身份与协商信息
+pub(crate) struct ClientHello {
+    pub uuid: [u8; 16],
+    pub version: ProtocolVersion,
+    /// 注册包末尾的能力位, v1 恒为 0
+    pub caps: u8,
 }
 
 pub(crate) struct SessionContext {
     pub session: Arc<Session>,
+    pub room: Arc<Room>,
     pub allow: Option<oneshot::Receiver<()>>,
     pub close: Option<oneshot::Receiver<()>>,
+    /// 恢复的会话: 恢复凭证与掉线期间缓冲的帧
+    pub resumed: Option<([u8; 16], Buffered)>,
+}
+
+/// 会话管道中尚未写出的帧, 按原顺序在新连接上重放
+pub(crate) struct Buffered {
+    pub rx: mpsc::Receiver<Outgoing>,
+    /// 连接断开时正在写出的帧
+    pub unsent: Option<Bytes>,
+}
+
+/// 宽限期内等待恢复的掉线客户端
+pub(crate) struct Suspended {
+    pub session_id: u16,
+    pub buffered: Buffered,
+    /// 通知原连接任务会话已被接管
+    pub claimed: oneshot::Sender<()>,
 }
 
 struct SessionAllocatorInner {
-    free_ids: VecDeque<u8>,
-    next_id: u8,
+    free_ids: BTreeSet<u16>,
+    next_id: u16,
 }
 
 impl SessionAllocatorInner {
     fn new() -> SessionAllocatorInner {
         Self {
-            free_ids: VecDeque::new(),
+            free_ids: BTreeSet::new(),
             next_id: 1,
         }
     }
@@ -44,52 +87,101 @@ impl SessionAllocator {
         }
     }
 
-    pub async fn allocate(&self) -> Option<u8> {
+    /// 优先复用最小的空闲 id, 使 v1 对端尽量能拿到 u8 范围内的 id
+    pub async fn allocate(&self, version: ProtocolVersion) -> Option<u16> {
+        let max_id = version.max_session_id();
         let mut allocator = self.inner.lock().await;
-        if let Some(id) = allocator.free_ids.pop_front() {
+        if let Some(&id) = allocator.free_ids.range(..=max_id).next() {
+            allocator.free_ids.remove(&id);
             return Some(id);
         }
 
-        if allocator.next_id == u8::MAX {
+        if allocator.next_id > max_id {
             return None;
         }
 
         let id = allocator.next_id;
-        allocator.next_id = allocator.next_id.wrapping_add(1);
-
-        if allocator.next_id == 0 {
-            allocator.next_id = 1;
-        }
-
+        allocator.next_id += 1;
         Some(id)
     }
 
-    pub async fn deallocate(&self, id: u8) {
+    pub async fn deallocate(&self, id: u16) {
         if id == 0 {
             return;
         }
 
         let mut inner = self.inner.lock().await;
-        inner.free_ids.push_back(id);
+        inner.free_ids.insert(id);
     }
 }
 
 impl Session {
-    pub fn new_client(tx: Tx, session_id: u8, client_id: [u8; 16]) -> Arc<Self> {
+    pub fn new_client(
+        tx: Tx,
+        session_id: u16,
+        hello: &ClientHello,
+        address: SocketAddr,
+        limiter: Option<Limiter>,
+        stats: Arc<SessionStats>,
+    ) -> Arc<Self> {
         Arc::new(Session {
             tx,
             role: Role::Client,
             session_id,
-            uuid: Some(client_id),
+            uuid: Some(hello.uuid),
+            version: hello.version,
+            address: Some(address),
+            limiter,
+            stats,
+            compression: hello.caps & CAP_COMPRESSION != 0,
+            latest: LatestSlots::default(),
         })
     }
 
-    pub fn new_server(tx: Tx, session_id: u8) -> Arc<Self> {
+    pub fn new_spectator(
+        tx: Tx,
+        session_id: u16,
+        hello: &ClientHello,
+        address: SocketAddr,
+        limiter: Option<Limiter>,
+        stats: Arc<SessionStats>,
+    ) -> Arc<Self> {
+        Arc::new(Session {
+            tx,
+            role: Role::Spectator,
+            session_id,
+            uuid: Some(hello.uuid),
+            version: hello.version,
+            address: Some(address),
+            limiter,
+            stats,
+            compression: hello.caps & CAP_COMPRESSION != 0,
+            latest: LatestSlots::default(),
+        })
+    }
+
+    pub fn new_server(
+        tx: Tx,
+        session_id: u16,
+        version: ProtocolVersion,
+        stats: Arc<SessionStats>,
+    ) -> Arc<Self> {
         Arc::new(Session {
             tx,
             role: Role::Server,
             session_id,
             uuid: None,
+            version,
+            address: None,
+            limiter: None,
+            stats,
+            compression: false,
+            latest: LatestSlots::default(),
         })
     }
+
+    /// 发送管道中尚未写出的帧数
+    pub fn queue_depth(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
 }
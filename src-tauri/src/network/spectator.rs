@@ -0,0 +1,50 @@
+use crate::network::states::Tx;
+use crate::network::stats::SessionStats;
+use bytes::Bytes;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// 延迟队列长度, 超出时丢弃
+const DELAY_QUEUE_LEN: usize = 1024;
+
+/// 旁观者的延迟画面: 广播帧先排队, 到点后再写入会话管道.
+/// 旁观者移除时结束转发任务, 未到点的帧随之丢弃
+pub(crate) struct DelayedFeed {
+    tx: mpsc::Sender<(Instant, Bytes)>,
+    delay: Duration,
+    task: JoinHandle<()>,
+}
+
+impl DelayedFeed {
+    pub fn spawn(session_tx: Tx, stats: Arc<SessionStats>, delay: Duration) -> Self {
+        let (tx, mut rx) = mpsc::channel::<(Instant, Bytes)>(DELAY_QUEUE_LEN);
+        let task = tokio::spawn(async move {
+            while let Some((due, frame)) = rx.recv().await {
+                sleep_until(due).await;
+                match session_tx.try_send(frame.into()) {
+                    Ok(_) => {}
+                    Err(TrySendError::Full(_)) => stats.record_drop(),
+                    Err(TrySendError::Closed(_)) => break,
+                }
+            }
+        });
+        DelayedFeed { tx, delay, task }
+    }
+
+    /// 排入延迟队列, 队列已满时返回 false
+    pub fn push(&self, frame: Bytes) -> bool {
+        self.tx
+            .try_send((Instant::now() + self.delay, frame))
+            .is_ok()
+    }
+}
+
+impl Drop for DelayedFeed {
+    fn drop(&mut self) {
+        // 转发任务持有会话管道, 不等队列排空, 以免拖住旁观者的连接收尾
+        self.task.abort();
+    }
+}
@@ -1,20 +1,41 @@
-use crate::network::session::Session;
-use ahash::AHashSet;
-use bytes::Bytes;
+use crate::network::auth::PasswordHash;
+use crate::network::ban::{save_bans, IpRange};
+use crate::network::config::RelayConfig;
+use crate::network::delivery::Outgoing;
+use crate::network::limit::{Limiter, Quota, SharedQuota};
+use crate::network::notice::NoticeCode;
+use crate::network::protocol::ClientDetails;
+use crate::network::recorder::{RecordKind, Recorder};
+use crate::network::session::{Session, Suspended};
+use crate::network::spectator::DelayedFeed;
+use crate::network::stats::{Counters, RelayStats, SessionStats, SessionTraffic};
+use crate::network::util::{constant_time_eq, format_uuid, now_ms};
+use crate::network::version::ProtocolVersion;
 use dashmap::iter::Iter;
 use dashmap::{DashMap, Entry};
+use log::error;
+use rand::Rng;
+use std::collections::HashSet;
 use std::net::IpAddr;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot, RwLock};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot, watch, RwLock};
+use tokio::time::Instant;
 
-pub type Tx = mpsc::Sender<Bytes>;
+pub type Tx = mpsc::Sender<Outgoing>;
+
+/// 旧版注册包 (无房间号) 使用的默认房间
+pub const DEFAULT_ROOM: u32 = 0;
+
+/// 单个房间的分组数上限
+pub const MAX_GROUPS: usize = 64;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Role {
     Server,
     Client,
+    /// 只接收广播, 不占用玩家席位
+    Spectator,
 }
 
 pub(crate) struct ClientEntry {
@@ -23,32 +44,84 @@ pub(crate) struct ClientEntry {
     close_tx: Option<oneshot::Sender<()>>,
 }
 
-pub(crate) struct RelayState {
+pub(crate) struct SpectatorEntry {
+    pub session: Arc<Session>,
+    /// 开启延迟旁观时的延迟队列
+    pub delayed: Option<DelayedFeed>,
+    close_tx: Option<oneshot::Sender<()>>,
+}
+
+/// 一局游戏: 一个服务端与其客户端, 路由与封禁均限定在房间内
+pub(crate) struct Room {
+    code: u32,
+    secret: [u8; 32],
     server: RwLock<Option<Arc<Session>>>,
-    clients: DashMap<u8, ClientEntry>,
-    client_uuids: DashMap<[u8; 16], u8>,
-    active: DashMap<u8, Arc<Session>>,
-    banned: RwLock<AHashSet<IpAddr>>,
-    shutting_down: AtomicBool,
+    clients: DashMap<u16, ClientEntry>,
+    client_uuids: DashMap<[u8; 16], u16>,
+    active: DashMap<u16, Arc<Session>>,
+    spectators: DashMap<u16, SpectatorEntry>,
+    /// 服务端定义的分组, 成员为客户端 session id
+    groups: DashMap<u16, HashSet<u16>>,
+    suspended: DashMap<[u8; 16], Suspended>,
+    banned: RwLock<Vec<IpRange>>,
+    /// 限时封禁, 不持久化, 到期后不再生效
+    expiring_bans: RwLock<Vec<(IpRange, Instant)>>,
+    /// 封禁列表的持久化文件, 仅默认房间设置
+    ban_file: Option<PathBuf>,
+    /// 当前服务端持有的迁移凭证, 新服务端可凭此接管房间
+    migration_token: Mutex<Option<[u8; 16]>>,
+    /// 服务端掉线后等待接管, 接管时通知原服务端的连接任务
+    migrating: Mutex<Option<oneshot::Sender<()>>>,
+    /// 入房密码, 未设置时不校验. 默认房间取自配置, 其它房间由 v2 REG_ROOM 设置
+    join_password: Mutex<Option<PasswordHash>>,
 }
 
-impl RelayState {
-    pub fn new() -> Self {
-        RelayState {
+impl Room {
+    fn new(code: u32, secret: [u8; 32], bans: Vec<IpRange>, ban_file: Option<PathBuf>) -> Self {
+        Room {
+            code,
+            secret,
             server: RwLock::new(None),
             clients: DashMap::new(),
             client_uuids: DashMap::new(),
             active: DashMap::new(),
-            banned: RwLock::new(AHashSet::new()),
-            shutting_down: AtomicBool::new(false),
+            spectators: DashMap::new(),
+            groups: DashMap::new(),
+            suspended: DashMap::new(),
+            banned: RwLock::new(bans),
+            expiring_bans: RwLock::new(Vec::new()),
+            ban_file,
+            migration_token: Mutex::new(None),
+            migrating: Mutex::new(None),
+            join_password: Mutex::new(None),
         }
     }
 
-    pub fn any_by_id(&self, session_id: &u8) -> Option<Arc<Session>> {
-        Some(self.clients.get(session_id)?.value().session.clone())
+    pub fn code(&self) -> u32 {
+        self.code
+    }
+
+    pub fn secret(&self) -> &[u8; 32] {
+        &self.secret
+    }
+
+    pub fn join_password(&self) -> Option<PasswordHash> {
+        *self.join_password.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set_join_password(&self, password: Option<PasswordHash>) {
+        *self.join_password.lock().unwrap_or_else(|e| e.into_inner()) = password;
+    }
+
+    /// 含未放行的客户端与旁观者
+    pub fn any_by_id(&self, session_id: &u16) -> Option<Arc<Session>> {
+        if let Some(entry) = self.clients.get(session_id) {
+            return Some(entry.value().session.clone());
+        }
+        Some(self.spectators.get(session_id)?.value().session.clone())
     }
 
-    pub fn by_id(&self, session_id: &u8) -> Option<Arc<Session>> {
+    pub fn by_id(&self, session_id: &u16) -> Option<Arc<Session>> {
         Some(self.active.get(session_id)?.value().clone())
     }
 
@@ -57,25 +130,95 @@ impl RelayState {
         self.by_id(&session_id)
     }
 
-    pub fn iter_clients(&self) -> Iter<'_, u8, ClientEntry> {
+    pub fn iter_clients(&self) -> Iter<'_, u16, ClientEntry> {
         self.clients.iter()
     }
 
-    pub fn iter(&self) -> Iter<'_, u8, Arc<Session>> {
+    pub fn iter(&self) -> Iter<'_, u16, Arc<Session>> {
         self.active.iter()
     }
 
+    pub fn iter_spectators(&self) -> Iter<'_, u16, SpectatorEntry> {
+        self.spectators.iter()
+    }
+
+    /// 玩家数, 不含旁观者
     pub fn size(&self) -> usize {
         self.clients.len()
     }
 
-    pub async fn register_server(&self, session: Arc<Session>) -> Result<(), &'static str> {
+    pub fn spectator_count(&self) -> usize {
+        self.spectators.len()
+    }
+
+    /// 是否有客户端的 session id 超出该版本的表示范围
+    pub fn has_unaddressable(&self, version: ProtocolVersion) -> bool {
+        self.clients.iter().any(|e| !version.can_encode(*e.key()))
+    }
+
+    /// 注册服务端, 返回 true 表示接管了迁移中的房间.
+    /// 房间内有该服务端版本无法表示的 session id 时拒绝, 以免这些客户端被静默略过
+    pub async fn register_server(&self, session: Arc<Session>) -> Result<bool, NoticeCode> {
         let mut guard = self.server.write().await;
         if guard.is_some() {
-            return Err("Server already exists");
+            return Err(NoticeCode::ServerExists);
+        }
+        if self.has_unaddressable(session.version) {
+            return Err(NoticeCode::UnaddressableClients);
         }
         *guard = Some(session);
-        Ok(())
+
+        let migrating = self
+            .migrating
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        Ok(migrating.is_some_and(|tx| tx.send(()).is_ok()))
+    }
+
+    /// 服务端掉线, 保留客户端并等待新服务端接管
+    pub async fn begin_migration(&self) -> oneshot::Receiver<()> {
+        let mut guard = self.server.write().await;
+        *guard = None;
+        let (tx, rx) = oneshot::channel::<()>();
+        *self.migrating.lock().unwrap_or_else(|e| e.into_inner()) = Some(tx);
+        rx
+    }
+
+    pub fn is_migrating(&self) -> bool {
+        self.migrating
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some()
+    }
+
+    /// 宽限期结束时放弃迁移, 与 register_server 互斥.
+    /// 返回 false 表示新服务端已先一步接管
+    pub async fn abandon_migration(&self) -> bool {
+        let _guard = self.server.write().await;
+        self.migrating
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+            .is_some()
+    }
+
+    /// 为新服务端生成迁移凭证, 旧凭证随之失效
+    pub fn issue_migration_token(&self) -> [u8; 16] {
+        let token: [u8; 16] = rand::random();
+        *self
+            .migration_token
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(token);
+        token
+    }
+
+    pub fn check_migration_token(&self, token: &[u8]) -> bool {
+        let guard = self
+            .migration_token
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        guard.is_some_and(|t| constant_time_eq(&t, token))
     }
 
     pub async fn get_server(&self) -> Option<Arc<Session>> {
@@ -86,13 +229,13 @@ impl RelayState {
         *self.server.write().await = None;
     }
 
-    pub fn register_client(&self, uuid: [u8; 16]) -> Entry<'_, [u8; 16], u8> {
+    pub fn register_client(&self, uuid: [u8; 16]) -> Entry<'_, [u8; 16], u16> {
         self.client_uuids.entry(uuid)
     }
 
     pub fn insert_client_entry(
         &self,
-        session_id: u8,
+        session_id: u16,
         session: Arc<Session>,
         permit_tx: oneshot::Sender<()>,
         close_tx: oneshot::Sender<()>,
@@ -107,11 +250,18 @@ impl RelayState {
         );
     }
 
-    pub fn active_client(&self, session_id: u8, session: Arc<Session>) {
+    pub fn active_client(&self, session_id: u16, session: Arc<Session>) {
         self.active.insert(session_id, session);
     }
 
-    pub fn permit(&self, session_id: &u8) {
+    /// 已注册但尚未被服务端放行
+    pub fn is_pending(&self, session_id: &u16) -> bool {
+        self.clients
+            .get(session_id)
+            .is_some_and(|entry| entry.permit_tx.is_some())
+    }
+
+    pub fn permit(&self, session_id: &u16) {
         if let Some(mut entry) = self.clients.get_mut(session_id) {
             if let Some(tx) = entry.permit_tx.take() {
                 let _ = tx.send(());
@@ -119,19 +269,103 @@ impl RelayState {
         }
     }
 
-    pub fn close(&self, session_id: &u8) {
+    pub fn close(&self, session_id: &u16) {
         if let Some(mut entry) = self.clients.get_mut(session_id) {
             if let Some(tx) = entry.close_tx.take() {
                 let _ = tx.send(());
             }
+        } else if let Some(mut entry) = self.spectators.get_mut(session_id) {
+            if let Some(tx) = entry.close_tx.take() {
+                let _ = tx.send(());
+            }
+        }
+    }
+
+    pub fn insert_spectator(
+        &self,
+        session: Arc<Session>,
+        delayed: Option<DelayedFeed>,
+        close_tx: oneshot::Sender<()>,
+    ) {
+        self.spectators.insert(
+            session.session_id,
+            SpectatorEntry {
+                session,
+                delayed,
+                close_tx: Some(close_tx),
+            },
+        );
+    }
+
+    pub fn remove_spectator(&self, session_id: u16) -> bool {
+        self.spectators.remove(&session_id).is_some()
+    }
+
+    /// 将客户端加入分组, 分组不存在时创建, 不在房间内的 id 被忽略.
+    /// 分组数已达上限时返回 false
+    pub fn join_group(&self, group: u16, ids: &[u16]) -> bool {
+        let ids: Vec<u16> = ids
+            .iter()
+            .copied()
+            .filter(|id| self.clients.contains_key(id))
+            .collect();
+        if !self.groups.contains_key(&group) && self.groups.len() >= MAX_GROUPS {
+            return false;
+        }
+        if !ids.is_empty() {
+            self.groups.entry(group).or_default().extend(ids);
+        }
+        true
+    }
+
+    /// 移出分组, 分组为空时一并移除
+    pub fn leave_group(&self, group: u16, ids: &[u16]) {
+        if let Entry::Occupied(mut entry) = self.groups.entry(group) {
+            for id in ids {
+                entry.get_mut().remove(id);
+            }
+            if entry.get().is_empty() {
+                entry.remove();
+            }
         }
     }
 
+    pub fn remove_group(&self, group: u16) {
+        self.groups.remove(&group);
+    }
+
+    pub fn group_members(&self, group: u16) -> Option<Vec<u16>> {
+        Some(self.groups.get(&group)?.iter().copied().collect())
+    }
+
+    /// 为恢复的连接换一个关闭通道, 旧连接的通道随之失效
+    pub fn renew_close(&self, session_id: &u16) -> Option<oneshot::Receiver<()>> {
+        let mut entry = self.clients.get_mut(session_id)?;
+        let (c_tx, c_rx) = oneshot::channel::<()>();
+        entry.close_tx = Some(c_tx);
+        Some(c_rx)
+    }
+
+    /// 掉线客户端保留席位, 期间发往它的帧留在会话管道中
+    pub fn suspend(&self, token: [u8; 16], suspended: Suspended) {
+        self.suspended.insert(token, suspended);
+    }
+
+    /// 取走等待恢复的会话, 恢复与超时清理以此决出唯一的所有者
+    pub fn take_suspended(&self, token: &[u8; 16]) -> Option<Suspended> {
+        Some(self.suspended.remove(token)?.1)
+    }
+
     /// NOT manually remove from only one map. Use this method to remove the session.
-    pub fn remove_by_id(&self, id: u8) -> Option<[u8; 16]> {
+    pub fn remove_by_id(&self, id: u16) -> Option<[u8; 16]> {
         let _ = self.active.remove(&id);
         let (_, entry) = self.clients.remove(&id)?;
 
+        self.groups.retain(|_, members| {
+            members.remove(&id);
+            !members.is_empty()
+        });
+
         let client_id = entry.session.uuid;
         if let Some(uuid) = client_id {
             self.client_uuids.remove(&uuid);
@@ -141,11 +375,14 @@ impl RelayState {
 
     pub fn clear_clients(&self) {
         self.client_uuids.clear();
+        self.suspended.clear();
         self.clients.clear();
         self.active.clear();
+        self.spectators.clear();
+        self.groups.clear();
     }
 
-    pub fn collect_client_list(&self) -> Vec<(u8, [u8; 16])> {
+    pub fn collect_client_list(&self) -> Vec<(u16, [u8; 16])> {
         self.clients
             .iter()
             .filter_map(|entry| {
@@ -155,34 +392,378 @@ impl RelayState {
             .collect()
     }
 
-    pub fn schedule_shutdown(&self) {
-        self.shutting_down.store(true, Ordering::Release);
+    /// QueryClients 扩展查询的客户端详情, 含未放行的客户端
+    pub fn collect_client_details(&self) -> Vec<ClientDetails> {
+        let now = now_ms() as u64;
+        self.clients
+            .iter()
+            .filter_map(|entry| {
+                let session = &entry.value().session;
+                Some(ClientDetails {
+                    session_id: *entry.key(),
+                    uuid: session.uuid?,
+                    rtt: session.stats.rtt(),
+                    pending: entry.value().permit_tx.is_some(),
+                    address: session.address,
+                    connected_at: session.stats.connected_at(),
+                    idle_ms: now.saturating_sub(session.stats.last_active()),
+                    queue_depth: session.queue_depth(),
+                })
+            })
+            .collect()
     }
 
-    pub fn is_shutdown(&self) -> bool {
-        self.shutting_down.load(Ordering::Acquire)
+    /// (session_id, 超额帧数, 超额字节数) 列表, 含未放行的客户端
+    pub fn collect_limit_stats(&self) -> Vec<(u16, u64, u64)> {
+        self.clients
+            .iter()
+            .filter_map(|entry| {
+                let (frames, bytes) = entry.value().session.limiter.as_ref()?.stats();
+                Some((*entry.key(), frames, bytes))
+            })
+            .collect()
+    }
+
+    /// 房间内服务端与所有客户端的流量
+    pub async fn collect_traffic(&self) -> Vec<SessionTraffic> {
+        let mut sessions: Vec<Arc<Session>> = self.get_server().await.into_iter().collect();
+        sessions.extend(self.clients.iter().map(|e| e.value().session.clone()));
+        sessions.extend(self.spectators.iter().map(|e| e.value().session.clone()));
+        sessions
+            .iter()
+            .map(|session| SessionTraffic {
+                room_code: self.code,
+                session_id: session.session_id,
+                is_server: session.role == Role::Server,
+                uuid: session.uuid.map(|id| format_uuid(&id)),
+                connected_at: session.stats.connected_at(),
+                last_active: session.stats.last_active(),
+                traffic: session.stats.snapshot(),
+                rtt: session.stats.rtt(),
+            })
+            .collect()
     }
 
     pub async fn is_banned(&self, ip: &IpAddr) -> bool {
         let guard = self.banned.read().await;
-        guard.contains(ip)
+        if guard.iter().any(|range| range.contains(ip)) {
+            return true;
+        }
+        let now = Instant::now();
+        let guard = self.expiring_bans.read().await;
+        guard
+            .iter()
+            .any(|(range, expires)| *expires > now && range.contains(ip))
+    }
+
+    /// 限时封禁, 同一地址段再次封禁时以新的到期时间为准
+    pub async fn ban_until(&self, range: IpRange, expires: Instant) {
+        let now = Instant::now();
+        let mut guard = self.expiring_bans.write().await;
+        guard.retain(|(r, e)| *e > now && *r != range);
+        guard.push((range, expires));
+    }
+
+    pub async fn ban(&self, range: IpRange) -> bool {
+        let mut guard = self.banned.write().await;
+        if guard.contains(&range) {
+            return false;
+        }
+        guard.push(range);
+        self.persist_bans(&guard).await;
+        true
+    }
+
+    /// 只移除完全相同的地址段
+    pub async fn unban(&self, range: &IpRange) -> bool {
+        let now = Instant::now();
+        let mut expiring = self.expiring_bans.write().await;
+        let before = expiring.len();
+        expiring.retain(|(r, e)| *e > now && r != range);
+        let removed = expiring.len() < before;
+        drop(expiring);
+
+        let mut guard = self.banned.write().await;
+        let Some(index) = guard.iter().position(|r| r == range) else {
+            return removed;
+        };
+        guard.remove(index);
+        self.persist_bans(&guard).await;
+        true
     }
 
-    pub async fn ban(&self, ip: IpAddr) -> bool {
-        self.banned.write().await.insert(ip)
+    /// 永久封禁与尚未到期的限时封禁
+    pub async fn bans(&self) -> Vec<IpRange> {
+        let mut bans = self.banned.read().await.clone();
+        let now = Instant::now();
+        let expiring = self.expiring_bans.read().await;
+        bans.extend(
+            expiring
+                .iter()
+                .filter(|(_, expires)| *expires > now)
+                .map(|(range, _)| *range),
+        );
+        bans
     }
 
-    pub async fn unban(&self, ip: &IpAddr) -> bool {
-        self.banned.write().await.remove(ip)
+    async fn persist_bans(&self, bans: &[IpRange]) {
+        let Some(path) = self.ban_file.as_ref() else {
+            return;
+        };
+        if let Err(e) = save_bans(path, bans).await {
+            error!("Failed to persist bans: {}", e);
+        }
+    }
+}
+
+pub(crate) struct RelayState {
+    config: RelayConfig,
+    rooms: DashMap<u32, Arc<Room>>,
+    ip_quotas: DashMap<IpAddr, SharedQuota>,
+    traffic: Arc<Counters>,
+    started_at: u64,
+    /// 置为 true 后各连接任务自行收尾
+    shutdown: watch::Sender<bool>,
+    /// 正在进行的流量录制
+    recorder: std::sync::RwLock<Option<Recorder>>,
+}
+
+impl RelayState {
+    /// 默认房间使用中继密钥, 兼容旧版注册包.
+    /// 持久化的封禁列表只作用于默认房间, 此处的入房密码也只设给默认房间
+    pub fn new(
+        config: RelayConfig,
+        secret: [u8; 32],
+        bans: Vec<IpRange>,
+        join_password: Option<PasswordHash>,
+    ) -> Self {
+        let rooms = DashMap::new();
+        let ban_file = config.ban_file.clone();
+        let default_room = Room::new(DEFAULT_ROOM, secret, bans, ban_file);
+        default_room.set_join_password(join_password);
+        rooms.insert(DEFAULT_ROOM, Arc::new(default_room));
+
+        RelayState {
+            config,
+            rooms,
+            ip_quotas: DashMap::new(),
+            traffic: Arc::new(Counters::default()),
+            started_at: now_ms() as u64,
+            shutdown: watch::Sender::new(false),
+            recorder: std::sync::RwLock::new(None),
+        }
+    }
+
+    pub fn config(&self) -> &RelayConfig {
+        &self.config
+    }
+
+    pub fn room(&self, code: u32) -> Option<Arc<Room>> {
+        Some(self.rooms.get(&code)?.value().clone())
+    }
+
+    /// 以随机房间号创建新房间
+    pub fn create_room(&self, secret: [u8; 32]) -> Option<Arc<Room>> {
+        if self.rooms.len() > self.config.max_rooms {
+            return None;
+        }
+
+        let mut rng = rand::thread_rng();
+        loop {
+            let code = rng.gen_range(100_000..1_000_000);
+            if let Entry::Vacant(v) = self.rooms.entry(code) {
+                let room = Arc::new(Room::new(code, secret, Vec::new(), None));
+                v.insert(room.clone());
+                return Some(room);
+            }
+        }
+    }
+
+    /// 默认房间常驻, 不会被移除
+    pub fn remove_room(&self, code: u32) {
+        if code != DEFAULT_ROOM {
+            self.rooms.remove(&code);
+        }
+    }
+
+    /// 新客户端的限流器, 同一 IP 的客户端共享 IP 配额
+    pub fn client_limiter(&self, ip: IpAddr) -> Option<Limiter> {
+        let session_limit = &self.config.session_limit;
+        let ip_limit = &self.config.ip_limit;
+        if session_limit.is_unlimited() && ip_limit.is_unlimited() {
+            return None;
+        }
+
+        let ip_quota = (!ip_limit.is_unlimited()).then(|| {
+            self.ip_quotas
+                .entry(ip)
+                .or_insert_with(|| Arc::new(Mutex::new(Quota::new(ip_limit))))
+                .clone()
+        });
+        Some(Limiter::new(session_limit, ip_quota))
+    }
+
+    /// 移除已无客户端引用的 IP 配额
+    pub fn prune_ip_quotas(&self) {
+        self.ip_quotas
+            .retain(|_, quota| Arc::strong_count(quota) > 1);
+    }
+
+    /// 新连接的流量计数, 累加到全局计数
+    pub fn session_stats(&self) -> Arc<SessionStats> {
+        Arc::new(SessionStats::new(self.traffic.clone()))
+    }
+
+    pub async fn stats(&self) -> RelayStats {
+        let rooms: Vec<Arc<Room>> = self.rooms.iter().map(|e| e.value().clone()).collect();
+        let mut sessions = Vec::new();
+        for room in &rooms {
+            sessions.extend(room.collect_traffic().await);
+        }
+
+        RelayStats {
+            started_at: self.started_at,
+            rooms: rooms.len(),
+            connections: sessions.len(),
+            total: self.traffic.snapshot(),
+            sessions,
+            recording: self.is_recording(),
+        }
+    }
+
+    /// 所有房间内的客户端总数
+    pub fn size(&self) -> usize {
+        self.rooms.iter().map(|room| room.size()).sum()
+    }
+
+    /// 所有房间内的旁观者总数
+    pub fn spectator_count(&self) -> usize {
+        self.rooms.iter().map(|room| room.spectator_count()).sum()
+    }
+
+    pub async fn clear_rooms(&self) {
+        let rooms: Vec<Arc<Room>> = self.rooms.iter().map(|e| e.value().clone()).collect();
+        for room in rooms {
+            room.clear_server().await;
+            room.clear_clients();
+        }
+        self.rooms.retain(|code, _| *code == DEFAULT_ROOM);
+    }
+
+    pub fn start_recording(&self, path: &Path, max_bytes: u64) -> Result<(), String> {
+        let mut guard = self.recorder.write().unwrap_or_else(|e| e.into_inner());
+        if guard.is_some() {
+            return Err("Already recording".into());
+        }
+        *guard = Some(Recorder::create(path, max_bytes)?);
+        Ok(())
+    }
+
+    /// 已开始录制且尚未因大小上限或写盘出错而停止
+    pub fn is_recording(&self) -> bool {
+        let guard = self.recorder.read().unwrap_or_else(|e| e.into_inner());
+        guard.as_ref().is_some_and(|r| !r.is_stopped())
+    }
+
+    pub fn take_recorder(&self) -> Option<Recorder> {
+        self.recorder
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+    }
+
+    /// 未在录制时什么也不做
+    pub fn record(&self, kind: RecordKind, room: u32, session_id: u16, data: &[u8]) {
+        let guard = self.recorder.read().unwrap_or_else(|e| e.into_inner());
+        if let Some(recorder) = guard.as_ref() {
+            recorder.record(kind, room, session_id, data);
+        }
+    }
+
+    pub fn record_attach(&self, room: u32, session: &Session) {
+        let guard = self.recorder.read().unwrap_or_else(|e| e.into_inner());
+        if let Some(recorder) = guard.as_ref() {
+            recorder.record_attach(room, session);
+        }
+    }
+
+    pub fn schedule_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// 中继开始关闭时返回, 已在关闭时立即返回
+    pub async fn shutdown_signal(&self) {
+        let mut rx = self.shutdown.subscribe();
+        let _ = rx.wait_for(|down| *down).await;
     }
 }
 
 pub struct ServerHandle {
+    /// 实际监听的端口, 配置为 0 时由系统分配
     pub port: u16,
-    pub secret: [u8; 32],
+    pub(crate) state: Arc<RelayState>,
 }
 
 pub struct ServerManager {
     pub handle: Option<ServerHandle>,
-    pub stop_tx: Option<oneshot::Sender<()>>,
+    /// 停止请求携带的回执在所有连接收尾、端口释放后触发
+    pub stop_tx: Option<oneshot::Sender<oneshot::Sender<()>>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::session::ClientHello;
+
+    fn client(version: ProtocolVersion, session_id: u16) -> Arc<Session> {
+        let (tx, _) = mpsc::channel(16);
+        let stats = Arc::new(SessionStats::new(Arc::new(Counters::default())));
+        let hello = ClientHello {
+            uuid: [session_id as u8; 16],
+            version,
+            caps: 0,
+        };
+        let address = "127.0.0.1:1".parse().unwrap();
+        Session::new_client(tx, session_id, &hello, address, None, stats)
+    }
+
+    fn server(version: ProtocolVersion) -> Arc<Session> {
+        let (tx, _) = mpsc::channel(16);
+        let stats = Arc::new(SessionStats::new(Arc::new(Counters::default())));
+        Session::new_server(tx, 1, version, stats)
+    }
+
+    fn room_with_client(session_id: u16) -> Room {
+        let room = Room::new(DEFAULT_ROOM, [0; 32], Vec::new(), None);
+        let client = client(ProtocolVersion::V2, session_id);
+        let (permit_tx, _) = oneshot::channel();
+        let (close_tx, _) = oneshot::channel();
+        room.insert_client_entry(session_id, client, permit_tx, close_tx);
+        room
+    }
+
+    #[tokio::test]
+    async fn v1_server_refused_when_ids_exceed_u8() {
+        let room = room_with_client(300);
+        let refused = room.register_server(server(ProtocolVersion::V1)).await;
+        assert_eq!(refused, Err(NoticeCode::UnaddressableClients));
+        assert!(room.get_server().await.is_none());
+        assert_eq!(
+            room.register_server(server(ProtocolVersion::V2)).await,
+            Ok(false)
+        );
+    }
+
+    #[tokio::test]
+    async fn v1_server_accepted_when_ids_fit() {
+        let room = room_with_client(200);
+        assert_eq!(
+            room.register_server(server(ProtocolVersion::V1)).await,
+            Ok(false)
+        );
+    }
 }
@@ -0,0 +1,237 @@
+use crate::network::util::now_ms;
+use bytes::Bytes;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::Notify;
+
+/// 一组流量计数
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    frames_in: AtomicU64,
+    bytes_in: AtomicU64,
+    frames_out: AtomicU64,
+    bytes_out: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Counters {
+    fn add_in(&self, len: usize) {
+        self.frames_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn add_out(&self, len: usize) {
+        self.frames_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn add_drop(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> TrafficStats {
+        TrafficStats {
+            frames_in: self.frames_in.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            frames_out: self.frames_out.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 由中继 ping 测得的往返时延, 平滑方式同 RFC 6298
+#[derive(Debug, Default)]
+struct RttEstimator {
+    next_seq: u64,
+    pending: Option<(u64, Instant)>,
+    sample: Option<RttStats>,
+}
+
+impl RttEstimator {
+    fn ping(&mut self) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        // 上一个 ping 未回应时视为丢失
+        self.pending = Some((seq, Instant::now()));
+        seq
+    }
+
+    fn pong(&mut self, seq: u64) {
+        let Some((pending, sent)) = self.pending else {
+            return;
+        };
+        if pending != seq {
+            return;
+        }
+        self.pending = None;
+
+        let rtt = sent.elapsed().as_secs_f64() * 1000.0;
+        self.sample = Some(match self.sample {
+            None => RttStats {
+                latest_ms: rtt,
+                smoothed_ms: rtt,
+                jitter_ms: rtt / 2.0,
+            },
+            Some(prev) => RttStats {
+                latest_ms: rtt,
+                smoothed_ms: prev.smoothed_ms * 0.875 + rtt * 0.125,
+                jitter_ms: prev.jitter_ms * 0.75 + (prev.smoothed_ms - rtt).abs() * 0.25,
+            },
+        });
+    }
+}
+
+/// 单个连接的流量计数, 同时累加到中继全局计数
+#[derive(Debug)]
+pub(crate) struct SessionStats {
+    counters: Counters,
+    global: Arc<Counters>,
+    connected_at: u64,
+    last_active: AtomicU64,
+    rtt: Mutex<RttEstimator>,
+    /// 发送队列越过高水位后置位, 回落到低水位时清除
+    congested: AtomicBool,
+    relieved: Notify,
+    /// 上次通知服务端时的丢弃计数
+    reported_drops: AtomicU64,
+}
+
+impl SessionStats {
+    pub fn new(global: Arc<Counters>) -> Self {
+        let now = now_ms() as u64;
+        Self {
+            counters: Counters::default(),
+            global,
+            connected_at: now,
+            last_active: AtomicU64::new(now),
+            rtt: Mutex::new(RttEstimator::default()),
+            congested: AtomicBool::new(false),
+            relieved: Notify::new(),
+            reported_drops: AtomicU64::new(0),
+        }
+    }
+
+    /// 收到对端的帧, 同时刷新最后活动时间
+    pub fn record_in(&self, len: usize) {
+        self.counters.add_in(len);
+        self.global.add_in(len);
+        self.last_active.store(now_ms() as u64, Ordering::Relaxed);
+    }
+
+    /// 帧已写出到对端
+    pub fn record_out(&self, len: usize) {
+        self.counters.add_out(len);
+        self.global.add_out(len);
+    }
+
+    /// 发送管道已满, 帧被丢弃
+    pub fn record_drop(&self) {
+        self.counters.add_drop();
+        self.global.add_drop();
+    }
+
+    /// 发送队列越过高水位, 首次越过时返回 true
+    pub fn enter_congestion(&self) -> bool {
+        !self.congested.swap(true, Ordering::Relaxed)
+    }
+
+    /// 发送队列回落到低水位, 由 relieved 的等待方通知服务端
+    pub fn leave_congestion(&self) {
+        if self.congested.load(Ordering::Relaxed) && self.congested.swap(false, Ordering::Relaxed) {
+            self.relieved.notify_one();
+        }
+    }
+
+    pub async fn relieved(&self) {
+        self.relieved.notified().await
+    }
+
+    /// 自上次调用以来丢弃的帧数
+    pub fn take_unreported_drops(&self) -> u64 {
+        let dropped = self.counters.dropped.load(Ordering::Relaxed);
+        dropped.saturating_sub(self.reported_drops.swap(dropped, Ordering::Relaxed))
+    }
+
+    /// 生成下一个 ping 的负载: [seq u64 LE]
+    pub fn ping_payload(&self) -> Bytes {
+        let seq = self.rtt.lock().unwrap_or_else(|e| e.into_inner()).ping();
+        Bytes::copy_from_slice(&seq.to_le_bytes())
+    }
+
+    /// 收到对端 pong, 负载不是本中继的 ping 时忽略
+    pub fn on_pong(&self, payload: &[u8]) {
+        let Ok(seq) = <[u8; 8]>::try_from(payload) else {
+            return;
+        };
+        let mut rtt = self.rtt.lock().unwrap_or_else(|e| e.into_inner());
+        rtt.pong(u64::from_le_bytes(seq));
+    }
+
+    /// 尚未测得时为 None
+    pub fn rtt(&self) -> Option<RttStats> {
+        self.rtt.lock().unwrap_or_else(|e| e.into_inner()).sample
+    }
+
+    pub fn connected_at(&self) -> u64 {
+        self.connected_at
+    }
+
+    pub fn last_active(&self) -> u64 {
+        self.last_active.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> TrafficStats {
+        self.counters.snapshot()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrafficStats {
+    pub frames_in: u64,
+    pub bytes_in: u64,
+    pub frames_out: u64,
+    pub bytes_out: u64,
+    /// 因发送管道已满而丢弃的帧数
+    pub dropped: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RttStats {
+    pub latest_ms: f64,
+    pub smoothed_ms: f64,
+    pub jitter_ms: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionTraffic {
+    pub room_code: u32,
+    pub session_id: u16,
+    pub is_server: bool,
+    pub uuid: Option<String>,
+    /// 毫秒时间戳
+    pub connected_at: u64,
+    /// 最后一次收到该连接帧的毫秒时间戳
+    pub last_active: u64,
+    pub traffic: TrafficStats,
+    pub rtt: Option<RttStats>,
+}
+
+/// relay_stats 命令的返回值
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RelayStats {
+    /// 中继启动的毫秒时间戳
+    pub started_at: u64,
+    pub rooms: usize,
+    pub connections: usize,
+    pub total: TrafficStats,
+    pub sessions: Vec<SessionTraffic>,
+    /// 正在录制; 录制达到大小上限后为 false, 结果仍由 stop_recording 返回
+    pub recording: bool,
+}
@@ -0,0 +1,104 @@
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+
+const CERT_FILE: &str = "relay-cert.pem";
+const KEY_FILE: &str = "relay-key.pem";
+
+/// wss:// 所用的证书链与私钥
+pub struct TlsIdentity {
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+}
+
+impl TlsIdentity {
+    /// 从 PEM 文件加载证书链与私钥
+    pub fn load_pem(cert_path: &Path, key_path: &Path) -> Result<Self, String> {
+        let cert_pem = fs::read(cert_path)
+            .map_err(|e| format!("Failed to read {}: {}", cert_path.display(), e))?;
+        let key_pem = fs::read(key_path)
+            .map_err(|e| format!("Failed to read {}: {}", key_path.display(), e))?;
+        Self::from_pem(&cert_pem, &key_pem)
+    }
+
+    /// 加载目录下的自签名证书, 不存在时生成并写入该目录
+    pub fn load_or_generate(dir: &Path) -> Result<Self, String> {
+        let cert_path = dir.join(CERT_FILE);
+        let key_path = dir.join(KEY_FILE);
+        if cert_path.is_file() && key_path.is_file() {
+            return Self::load_pem(&cert_path, &key_path);
+        }
+
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .map_err(|e| format!("Failed to generate certificate: {}", e))?;
+        let cert_pem = generated.cert.pem();
+        let key_pem = generated.key_pair.serialize_pem();
+
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        fs::write(&cert_path, &cert_pem)
+            .map_err(|e| format!("Failed to write {}: {}", cert_path.display(), e))?;
+        write_private(&key_path, key_pem.as_bytes())
+            .map_err(|e| format!("Failed to write {}: {}", key_path.display(), e))?;
+
+        Self::from_pem(cert_pem.as_bytes(), key_pem.as_bytes())
+    }
+
+    fn from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<Self, String> {
+        let certs = CertificateDer::pem_slice_iter(cert_pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid certificate PEM: {}", e))?;
+        if certs.is_empty() {
+            return Err("No certificate found in PEM".into());
+        }
+
+        let key = PrivateKeyDer::from_pem_slice(key_pem)
+            .map_err(|e| format!("Invalid key PEM: {}", e))?;
+
+        Ok(Self { certs, key })
+    }
+
+    /// 叶证书 DER 的 SHA-256 指纹, 格式如 "AB:CD:..."
+    pub fn fingerprint(&self) -> String {
+        Sha256::digest(self.certs[0].as_ref())
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+
+    pub(crate) fn acceptor(&self) -> Result<TlsAcceptor, String> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format!("TLS config error: {}", e))?
+            .with_no_client_auth()
+            .with_single_cert(self.certs.clone(), self.key.clone_key())
+            .map_err(|e| format!("TLS config error: {}", e))?;
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// 写入私钥文件, unix 下创建时即限定为仅属主可读写
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    // 旧文件的权限不会被 mode 覆盖, 先删除再重新创建
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
@@ -1,3 +1,4 @@
+use crate::network::version::ProtocolVersion;
 use std::net::Ipv4Addr;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -16,14 +17,24 @@ pub fn format_uuid(bytes: &[u8; 16]) -> String {
     )
 }
 
-pub fn parse_session_id(cursor: &[u8], count: usize) -> Result<(Vec<u8>, &[u8]), &'static str> {
-    if cursor.len() < count {
+pub fn parse_session_id(
+    mut cursor: &[u8],
+    count: usize,
+    version: ProtocolVersion,
+) -> Result<(Vec<u16>, &[u8]), &'static str> {
+    if cursor.len() < count * version.id_len() {
         return Err("Not enough bytes for parsing");
     }
 
-    let excludes = cursor[..count].to_vec();
-    let rest = &cursor[count..];
-    Ok((excludes, rest))
+    let mut excludes = Vec::with_capacity(count);
+    for _ in 0..count {
+        let (id, rest) = version
+            .read_id(cursor)
+            .ok_or("Not enough bytes for parsing")?;
+        excludes.push(id);
+        cursor = rest;
+    }
+    Ok((excludes, cursor))
 }
 
 pub fn read_var_uint(mut buf: &[u8]) -> Result<(u32, &[u8]), &'static str> {
@@ -69,6 +80,20 @@ pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
         == 0
 }
 
+/// 解析 64 位十六进制的中继密钥
+pub fn decode_secret(hex: &str) -> Result<[u8; 32], &'static str> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err("Secret must be 64 hex chars");
+    }
+
+    let mut secret = [0u8; 32];
+    for (i, byte) in secret.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| "Secret must be 64 hex chars")?;
+    }
+    Ok(secret)
+}
+
 pub fn parse_ipv4(data: &[u8]) -> Option<Ipv4Addr> {
     if data.len() < 4 {
         return None;
@@ -0,0 +1,185 @@
+use crate::network::compress::compress_frame;
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// 注册包中的协议版本.
+/// v1: session id 为 u8, 注册包不带版本字节;
+/// v2: session id 为 u16 LE, 注册包在头部后带版本字节
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    V1,
+    V2,
+}
+
+impl ProtocolVersion {
+    pub const V2_BYTE: u8 = 0x02;
+
+    /// 可分配给该版本对端的最大 session id
+    pub fn max_session_id(self) -> u16 {
+        match self {
+            ProtocolVersion::V1 => u8::MAX as u16 - 1,
+            ProtocolVersion::V2 => u16::MAX - 1,
+        }
+    }
+
+    /// 按长度识别注册包版本: v1 为 [Header][Body], v2 为 [Header][0x02][Body]
+    pub fn split_register(packet: &[u8], body_len: usize) -> Option<(ProtocolVersion, &[u8])> {
+        if packet.len() == 1 + body_len {
+            return Some((ProtocolVersion::V1, &packet[1..]));
+        }
+        if packet.len() == 2 + body_len && packet[1] == Self::V2_BYTE {
+            return Some((ProtocolVersion::V2, &packet[2..]));
+        }
+        None
+    }
+
+    /// 版本号, 用于录制文件等
+    pub fn number(self) -> u8 {
+        match self {
+            ProtocolVersion::V1 => 1,
+            ProtocolVersion::V2 => Self::V2_BYTE,
+        }
+    }
+
+    /// number 的逆过程, 未知版本号返回 None
+    pub fn from_number(number: u8) -> Option<Self> {
+        match number {
+            1 => Some(ProtocolVersion::V1),
+            Self::V2_BYTE => Some(ProtocolVersion::V2),
+            _ => None,
+        }
+    }
+
+    /// 同 split_register, 但 v2 注册包可在末尾多带一个能力字节; 未携带时为 0
+    pub fn split_register_caps(
+        packet: &[u8],
+        body_len: usize,
+    ) -> Option<(ProtocolVersion, &[u8], u8)> {
+        if let Some((version, body)) = Self::split_register(packet, body_len) {
+            return Some((version, body, 0));
+        }
+        if packet.len() == 3 + body_len && packet[1] == Self::V2_BYTE {
+            return Some((
+                ProtocolVersion::V2,
+                &packet[2..2 + body_len],
+                packet[2 + body_len],
+            ));
+        }
+        None
+    }
+
+    pub fn id_len(self) -> usize {
+        match self {
+            ProtocolVersion::V1 => 1,
+            ProtocolVersion::V2 => 2,
+        }
+    }
+
+    pub fn can_encode(self, id: u16) -> bool {
+        id <= self.max_session_id()
+    }
+
+    /// 读取一个 session id, 返回剩余字节
+    pub fn read_id(self, buf: &[u8]) -> Option<(u16, &[u8])> {
+        match self {
+            ProtocolVersion::V1 => {
+                let (&id, rest) = buf.split_first()?;
+                Some((id as u16, rest))
+            }
+            ProtocolVersion::V2 => {
+                if buf.len() < 2 {
+                    return None;
+                }
+                Some((u16::from_le_bytes([buf[0], buf[1]]), &buf[2..]))
+            }
+        }
+    }
+
+    pub fn put_id(self, buf: &mut BytesMut, id: u16) {
+        match self {
+            ProtocolVersion::V1 => buf.put_u8(id as u8),
+            ProtocolVersion::V2 => buf.put_u16_le(id),
+        }
+    }
+
+    /// 编码 [Header][Id][Body] 形式的帧, id 超出该版本范围时返回 None
+    pub fn encode_frame(self, header: u8, id: u16, body: &[u8]) -> Option<Bytes> {
+        if !self.can_encode(id) {
+            return None;
+        }
+
+        let mut buf = BytesMut::with_capacity(1 + self.id_len() + body.len());
+        buf.put_u8(header);
+        self.put_id(&mut buf, id);
+        buf.put_slice(body);
+        Some(buf.freeze())
+    }
+}
+
+/// 按接收方版本惰性编码的同一帧, 扇出时每个版本只编码一次
+pub struct VersionedFrame<'a> {
+    header: u8,
+    id: u16,
+    body: &'a [u8],
+    v1: Option<Option<Bytes>>,
+    v2: Option<Option<Bytes>>,
+    compressed_v1: Option<Option<Bytes>>,
+    compressed_v2: Option<Option<Bytes>>,
+}
+
+impl<'a> VersionedFrame<'a> {
+    pub fn new(header: u8, id: u16, body: &'a [u8]) -> Self {
+        Self {
+            header,
+            id,
+            body,
+            v1: None,
+            v2: None,
+            compressed_v1: None,
+            compressed_v2: None,
+        }
+    }
+
+    /// 直接复用发送方的原始帧, 避免同版本重复编码
+    pub fn with_original(mut self, version: ProtocolVersion, frame: Bytes) -> Self {
+        match version {
+            ProtocolVersion::V1 => self.v1 = Some(Some(frame)),
+            ProtocolVersion::V2 => self.v2 = Some(Some(frame)),
+        }
+        self
+    }
+
+    pub fn get(&mut self, version: ProtocolVersion) -> Option<&Bytes> {
+        let (header, id, body) = (self.header, self.id, self.body);
+        let slot = match version {
+            ProtocolVersion::V1 => &mut self.v1,
+            ProtocolVersion::V2 => &mut self.v2,
+        };
+        slot.get_or_insert_with(|| version.encode_frame(header, id, body))
+            .as_ref()
+    }
+
+    /// 压缩后的帧, 每个版本只压缩一次; 压缩无收益时退回未压缩的帧
+    pub fn get_compressed(&mut self, version: ProtocolVersion) -> Option<&Bytes> {
+        let done = match version {
+            ProtocolVersion::V1 => self.compressed_v1.is_some(),
+            ProtocolVersion::V2 => self.compressed_v2.is_some(),
+        };
+        if !done {
+            let compressed = self.get(version).and_then(|frame| compress_frame(frame));
+            match version {
+                ProtocolVersion::V1 => self.compressed_v1 = Some(compressed),
+                ProtocolVersion::V2 => self.compressed_v2 = Some(compressed),
+            }
+        }
+
+        let compressed = match version {
+            ProtocolVersion::V1 => matches!(self.compressed_v1, Some(Some(_))),
+            ProtocolVersion::V2 => matches!(self.compressed_v2, Some(Some(_))),
+        };
+        match (compressed, version) {
+            (true, ProtocolVersion::V1) => self.compressed_v1.as_ref()?.as_ref(),
+            (true, ProtocolVersion::V2) => self.compressed_v2.as_ref()?.as_ref(),
+            (false, _) => self.get(version),
+        }
+    }
+}
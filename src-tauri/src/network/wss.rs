@@ -1,48 +1,81 @@
-use crate::network::cmd::is_open;
+use crate::network::auth::{challenge_nonce, PasswordHash};
+use crate::network::ban::IpRange;
+use crate::network::config::LimitPolicy;
+use crate::network::delivery::{Delivery, Outgoing};
+use crate::network::fragment::Reassembly;
 use crate::network::header::*;
+use crate::network::limit::Limiter;
+use crate::network::notice::{Notice, NoticeCode};
 use crate::network::protocol::*;
-use crate::network::session::{Session, SessionContext, NEXT_SESSION_ID};
-use crate::network::states::{RelayState, Role, ServerManager, Tx};
+use crate::network::recorder::RecordKind;
+use crate::network::relay::is_open;
+use crate::network::session::{
+    Buffered, ClientHello, Session, SessionContext, Suspended, NEXT_SESSION_ID,
+};
+use crate::network::spectator::DelayedFeed;
+use crate::network::states::{RelayState, Role, Room, ServerManager, Tx, DEFAULT_ROOM};
+use crate::network::stats::SessionStats;
+use crate::network::stream::RelayStream;
 use crate::network::util::{
-    constant_time_eq, format_uuid, is_nil_uuid, now_ms, parse_ipv4, parse_session_id, read_var_uint,
+    constant_time_eq, format_uuid, is_nil_uuid, now_ms, parse_session_id, read_var_uint,
 };
-use bytes::{Buf, BufMut, BytesMut};
+use crate::network::version::{ProtocolVersion, VersionedFrame};
+use bytes::Buf;
 use dashmap::Entry;
-use futures_util::stream::SplitStream;
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use log::{error, info, warn};
+use std::net::SocketAddr;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::{mpsc, oneshot, Mutex, OnceCell};
-use tokio::time::{timeout, Duration};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{
+    interval_at, sleep_until, timeout, Duration, Instant, Interval, MissedTickBehavior,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::{Bytes, Error, Message};
 use tokio_tungstenite::{accept_async, WebSocketStream};
 
 pub static SERVER_MANAGER: OnceCell<Mutex<ServerManager>> = OnceCell::const_new();
 pub static OPEN_FLAG: OnceCell<AtomicBool> = OnceCell::const_new_with(AtomicBool::new(false));
 
-const MAX_PAYLOAD_LEN: usize = 6144; // 6 KB upper bound for a single frame
-const MAX_EXCLUDES: u32 = 16; // exclude uuid count
 const MAX_BACKOFF: Duration = Duration::from_secs(5);
-const MAX_CONNECTIONS: usize = 64; // u8 session id space upper bound with margin
+/// 关闭中继时等待连接收尾的期限, 超时后强行结束
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// 取回写端的期限, 写出任务正常情况下会立即放弃进行中的写出
+const DETACH_TIMEOUT: Duration = Duration::from_secs(2);
+/// 连接收尾时写出剩余帧的期限, 须短于 SHUTDOWN_TIMEOUT
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(3);
+
+type WsReader = SplitStream<WebSocketStream<RelayStream>>;
+type WsWriter = SplitSink<WebSocketStream<RelayStream>, Message>;
 
 pub async fn run_ws_server(
     listener: TcpListener,
     state: Arc<RelayState>,
-    mut stop_receiver: oneshot::Receiver<()>,
+    tls: Option<TlsAcceptor>,
+    mut stop_receiver: oneshot::Receiver<oneshot::Sender<()>>,
 ) {
     let mut backoff = Duration::from_millis(100);
     let mut consecutive_errors = 0u32;
+    let mut connections = JoinSet::new();
+    let mut done_tx = None;
 
     loop {
         tokio::select! {
-                _ = &mut stop_receiver => {
+                res = &mut stop_receiver => {
                     info!("Shutting down relay server");
                     state.schedule_shutdown();
+                    done_tx = res.ok();
                     break;
                 }
+                // 回收已结束的连接任务
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
                 accept_res = listener.accept(), if !state.is_shutdown() => {
                     match accept_res {
                     Ok((stream,address)) => {
@@ -55,20 +88,28 @@ pub async fn run_ws_server(
                             continue;
                         }
 
-                        let is_banned = state.is_banned(&address.ip()).await;
+                        // 默认房间的封禁在握手前拒绝, 其他房间在注册时检查
+                        let is_banned = match state.room(DEFAULT_ROOM) {
+                            Some(room) => room.is_banned(&address.ip()).await,
+                            None => false,
+                        };
                         if is_banned {
                             info!("A banned IP attempt to connect {}", address);
                             continue;
                         }
 
-                        if state.size() >= MAX_CONNECTIONS {
-                            warn!("Connection limit reached ({}), rejecting {}", MAX_CONNECTIONS, address);
+                        // 玩家与旁观者的上限在注册时分别检查
+                        let config = state.config();
+                        let max_connections = config.max_connections + config.max_spectators;
+                        if state.size() + state.spectator_count() >= max_connections {
+                            warn!("Connection limit reached ({}), rejecting {}", max_connections, address);
                             drop(stream);
                             continue;
                         }
 
                         let state = state.clone();
-                        tokio::spawn(async move { handle_connection(stream, state).await; });
+                        let tls = tls.clone();
+                        connections.spawn(async move { handle_connection(stream, state, tls).await; });
                     }
                     Err(e) => {
                         error!("Accept error: {}", e);
@@ -85,9 +126,19 @@ pub async fn run_ws_server(
         }}
     }
 
+    // 不再接受新连接, 各连接收到关闭信号后通知对端并自行收尾
+    drop(listener);
+    let drain = async { while connections.join_next().await.is_some() {} };
+    if timeout(SHUTDOWN_TIMEOUT, drain).await.is_err() {
+        warn!(
+            "{} connections did not close in time, aborting",
+            connections.len()
+        );
+        connections.shutdown().await;
+    }
+
     // 兜底清理
-    state.clear_server().await;
-    state.clear_clients();
+    state.clear_rooms().await;
     info!("Relay server shutdown");
 
     if let Some(state_cell) = SERVER_MANAGER.get() {
@@ -95,17 +146,21 @@ pub async fn run_ws_server(
         guard.handle = None;
         guard.stop_tx = None;
     }
+    if let Some(done_tx) = done_tx {
+        let _ = done_tx.send(());
+    }
 }
 
-async fn handle_connection(stream: TcpStream, state: Arc<RelayState>) {
+async fn handle_connection(stream: TcpStream, state: Arc<RelayState>, tls: Option<TlsAcceptor>) {
     if state.is_shutdown() {
         info!("Rejecting new connection: server shutting down");
         return;
     }
 
-    match stream.peer_addr() {
+    let address = match stream.peer_addr() {
         Ok(a) => {
             info!("New connection received {}", a);
+            a
         }
         Err(e) => {
             error!("Failed to get peer address: {}", e);
@@ -113,6 +168,21 @@ async fn handle_connection(stream: TcpStream, state: Arc<RelayState>) {
         }
     };
 
+    let stream = match tls {
+        Some(acceptor) => match timeout(Duration::from_secs(5), acceptor.accept(stream)).await {
+            Ok(Ok(s)) => RelayStream::Tls(Box::new(s)),
+            Ok(Err(e)) => {
+                error!("TLS handshake failed: {}", e);
+                return;
+            }
+            Err(_) => {
+                error!("TLS handshake timeout");
+                return;
+            }
+        },
+        None => RelayStream::Plain(stream),
+    };
+
     let ws_stream = match accept_async(stream).await {
         Ok(ws) => ws,
         Err(e) => {
@@ -122,83 +192,133 @@ async fn handle_connection(stream: TcpStream, state: Arc<RelayState>) {
     };
 
     // tcp + 消息管道
-    let (mut writer, mut reader) = ws_stream.split();
-    let (tx, mut rx) = mpsc::channel::<Bytes>(256);
+    let (writer, mut reader) = ws_stream.split();
+    let (tx, rx) = mpsc::channel::<Outgoing>(state.config().queue_len);
 
     // 向此连接发送
-    let send_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if let Err(e) = writer.send(Message::Binary(msg)).await {
-                error!("WebSocket write failed: {}", e);
-                drop(rx);
-                let _ = writer.close().await;
-                break;
-            }
-        }
-    });
+    let stats = state.session_stats();
+    let buffered = Buffered { rx, unsent: None };
+    let ping_interval = state.config().ping_interval;
+    let mut send_task = Some(spawn_writer(writer, buffered, stats.clone(), ping_interval));
 
     info!("Start to registry {}", now_ms());
 
-    let ctx = match attach_session(&state, tx, &mut reader).await {
+    let ctx = match attach_session(&state, tx, stats, &mut reader, address).await {
         Ok(s) => s,
         Err(e) => {
             warn!("Registration failed: {}", e);
+            state.prune_ip_quotas();
+            if let Some(task) = send_task {
+                task.finish(shutdown_frame(&state)).await;
+            }
             return;
         }
     };
 
     // 向对端发送
     let session = ctx.session;
+    let room = ctx.room;
+    state.record_attach(room.code(), &session);
+    let mut timed_out = false;
     match session.role {
         Role::Client => {
-            if let Some((allow_rx, mut close_rx)) = ctx.allow.zip(ctx.close) {
-                info!("Client {} waiting release", session.session_id);
-                let is_allow = tokio::select! {
-                    _ = allow_rx => true,
-                    _ = &mut close_rx => false,
-                    _ = tokio::time::sleep(Duration::from_secs(4)) => false,
+            if let Some(mut close_rx) = ctx.close {
+                let (admitted, token) = match ctx.resumed {
+                    Some((token, buffered)) => {
+                        // 先发 Attached, 再按序重放掉线期间缓冲的帧
+                        let packet = Attached {
+                            session_id: session.session_id,
+                            version: session.version,
+                        };
+                        send_task = match send_task.take() {
+                            Some(task) => {
+                                let stats = session.stats.clone();
+                                task.resume(packet.to_bytes(), buffered, stats).await
+                            }
+                            None => None,
+                        };
+                        (send_task.is_some(), Some(token))
+                    }
+                    None => admit_client(&state, &room, &session, ctx.allow, &mut close_rx).await,
                 };
 
-                info!("Client {} released {}", session.session_id, is_allow);
-                if is_allow {
-                    state.active_client(session.session_id, session.clone());
-
-                    let packet = Attached {
-                        session_id: session.session_id,
-                    };
-                    send_packet(&session.tx, packet, Duration::from_secs(2)).await;
-                    client_relay(&state, &session, &mut reader, close_rx).await;
+                if admitted {
+                    let reason =
+                        client_relay(&state, &room, &session, &mut reader, &mut close_rx).await;
+                    timed_out = reason == Disconnect::TimedOut;
+                    if let (Disconnect::Dropped, Some(token)) = (reason, token) {
+                        if let Some(task) = send_task.take() {
+                            if suspend_client(&state, &room, &session, token, task, close_rx).await
+                            {
+                                info!(
+                                    "Client {} taken over by a new connection",
+                                    session.session_id
+                                );
+                                return;
+                            }
+                        }
+                    }
                 }
             }
 
             // 清理
-            if let Some(id) = state.remove_by_id(session.session_id) {
+            if let Some(id) = room.remove_by_id(session.session_id) {
                 info!("Client disconnected with id: {}", format_uuid(&id));
-                if let Some(server) = state.get_server().await {
+                if let Some(server) = room.get_server().await {
                     let packet = Detached {
                         session_id: session.session_id,
+                        version: server.version,
                     };
                     send_packet(&server.tx, packet, Duration::from_secs(2)).await;
                 }
             }
         }
+        Role::Spectator => {
+            if let Some(mut close_rx) = ctx.close {
+                let reason = spectator_relay(&state, &session, &mut reader, &mut close_rx).await;
+                timed_out = reason == Disconnect::TimedOut;
+            }
+
+            // 清理
+            if room.remove_spectator(session.session_id) {
+                info!("Spectator {} disconnected", session.session_id);
+                if let Some(server) = room.get_server().await {
+                    if server.version == ProtocolVersion::V2 {
+                        let packet = Detached {
+                            session_id: session.session_id,
+                            version: server.version,
+                        };
+                        send_packet(&server.tx, packet, Duration::from_secs(2)).await;
+                    }
+                }
+            }
+        }
         Role::Server => {
-            server_relay(&state, &session, &mut reader).await;
+            let reason = server_relay(&state, &room, &session, &mut reader).await;
+            timed_out = reason == Disconnect::TimedOut;
 
             // 清理
-            if state
+            if room
                 .get_server()
                 .await
                 .as_ref()
                 .map(|s| Arc::ptr_eq(s, &session))
                 .unwrap_or(false)
             {
-                info!("Server disconnected");
-                let ids: Vec<u8> = state.iter_clients().map(|e| *e.key()).collect();
-                for id in ids {
-                    state.close(&id);
+                info!("Server of room {} disconnected", room.code());
+                if migrate_server(&state, &room).await {
+                    info!("Room {} taken over by a new server", room.code());
+                } else {
+                    let ids: Vec<u16> = room
+                        .iter_clients()
+                        .map(|e| *e.key())
+                        .chain(room.iter_spectators().map(|e| *e.key()))
+                        .collect();
+                    for id in ids {
+                        room.close(&id);
+                    }
+                    state.remove_room(room.code());
                 }
-                state.clear_server().await;
             }
         }
     }
@@ -208,155 +328,939 @@ async fn handle_connection(stream: TcpStream, state: Arc<RelayState>) {
         session.role, session.session_id
     );
     info!("Left {} connections", state.size());
+    state.record(RecordKind::Detach, room.code(), session.session_id, &[]);
 
+    let close = shutdown_frame(&state);
+    if close.is_some() {
+        send_notice(&session.tx, session.version, NoticeCode::RelayShutdown);
+    }
     NEXT_SESSION_ID.deallocate(session.session_id).await;
     drop(session);
-    if let Err(e) = send_task.await {
-        info!("Send task panicked: {}", e);
+    state.prune_ip_quotas();
+    if let Some(task) = send_task {
+        if timed_out {
+            task.abort();
+        } else {
+            task.finish(close).await;
+        }
     }
 }
 
-/// 0x01 = 注册为 Server
-/// 0x02 = 注册为 Client + 后续字节是 client_id
-async fn attach_session(
+/// 向连接写出会话管道中的帧, 停止时交还写端与剩余帧以便会话恢复
+/// 按 ping_interval 发送 ping, 用于测量往返时延并让对端的 pong 续期心跳
+struct SendTask {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<(WsWriter, Buffered)>,
+    ping_interval: Duration,
+}
+
+fn spawn_writer(
+    mut writer: WsWriter,
+    buffered: Buffered,
+    stats: Arc<SessionStats>,
+    ping_interval: Duration,
+) -> SendTask {
+    let (stop, mut stop_rx) = oneshot::channel::<()>();
+    let task = tokio::spawn(async move {
+        let Buffered { mut rx, mut unsent } = buffered;
+        let mut ping = (!ping_interval.is_zero()).then(|| {
+            let mut ping = interval_at(Instant::now() + ping_interval, ping_interval);
+            ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ping
+        });
+        loop {
+            let msg = match unsent.take() {
+                Some(msg) => msg,
+                None => tokio::select! {
+                    biased;
+                    _ = &mut stop_rx => break,
+                    // 排在管道之前, 持续有帧写出时 ping 也不会被饿死
+                    _ = next_ping(&mut ping) => {
+                        let ping = Message::Ping(stats.ping_payload());
+                        match write_or_stop(&mut writer, ping, &mut stop_rx).await {
+                            Some(Ok(())) => continue,
+                            Some(Err(e)) => error!("WebSocket ping failed: {}", e),
+                            None => {}
+                        }
+                        break;
+                    }
+                    msg = rx.recv() => match msg {
+                        Some(msg) => match msg.into_frame() {
+                            Some(frame) => frame,
+                            None => continue,
+                        },
+                        None => break,
+                    },
+                },
+            };
+
+            let len = msg.len();
+            match write_or_stop(&mut writer, Message::Binary(msg.clone()), &mut stop_rx).await {
+                Some(Ok(())) => {}
+                Some(Err(e)) => {
+                    error!("WebSocket write failed: {}", e);
+                    unsent = Some(msg);
+                    break;
+                }
+                // 对端不再读取时写出可能一直挂起, 留待恢复后重发
+                None => {
+                    unsent = Some(msg);
+                    break;
+                }
+            }
+            stats.record_out(len);
+            if rx.len() <= low_watermark(rx.max_capacity()) {
+                stats.leave_congestion();
+            }
+        }
+        (writer, Buffered { rx, unsent })
+    });
+
+    SendTask {
+        stop,
+        task,
+        ping_interval,
+    }
+}
+
+/// 写出一条消息, 期间收到停止信号时放弃并返回 None
+async fn write_or_stop(
+    writer: &mut WsWriter,
+    msg: Message,
+    stop_rx: &mut oneshot::Receiver<()>,
+) -> Option<Result<(), Error>> {
+    tokio::select! {
+        biased;
+        _ = stop_rx => None,
+        res = writer.send(msg) => Some(res),
+    }
+}
+
+async fn next_ping(ping: &mut Option<Interval>) {
+    match ping {
+        Some(ping) => {
+            ping.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+impl SendTask {
+    /// 立即停止写出, 取回写端与尚未写出的帧; 超时未取回时结束写出任务并返回 None
+    async fn detach(self) -> Option<(WsWriter, Buffered)> {
+        let SendTask { stop, mut task, .. } = self;
+        let _ = stop.send(());
+        match timeout(DETACH_TIMEOUT, &mut task).await {
+            Ok(Ok(parts)) => Some(parts),
+            Ok(Err(e)) => {
+                info!("Send task panicked: {}", e);
+                None
+            }
+            Err(_) => {
+                warn!("Send task did not stop in time, aborting");
+                task.abort();
+                None
+            }
+        }
+    }
+
+    /// 对端已失联, 直接结束写出任务, 不等待管道写完
+    fn abort(self) {
+        self.task.abort();
+    }
+
+    /// 先写出 first, 再改为写出恢复会话的缓冲帧
+    async fn resume(
+        self,
+        first: Bytes,
+        buffered: Buffered,
+        stats: Arc<SessionStats>,
+    ) -> Option<SendTask> {
+        let ping_interval = self.ping_interval;
+        let (mut writer, _) = self.detach().await?;
+        let len = first.len();
+        if let Err(e) = writer.send(Message::Binary(first)).await {
+            error!("WebSocket write failed: {}", e);
+            return None;
+        }
+        stats.record_out(len);
+        Some(spawn_writer(writer, buffered, stats, ping_interval))
+    }
+
+    /// 等待管道中的帧全部写出后关闭连接, 中继关闭时带上关闭原因.
+    /// 对端迟迟不读时超过 FLUSH_TIMEOUT 即放弃
+    async fn finish(self, close: Option<CloseFrame>) {
+        let SendTask { stop, mut task, .. } = self;
+        let flush = async {
+            match (&mut task).await {
+                Ok((mut writer, _)) => {
+                    if let Some(frame) = close {
+                        let _ = writer.send(Message::Close(Some(frame))).await;
+                    }
+                    let _ = writer.close().await;
+                }
+                Err(e) => info!("Send task panicked: {}", e),
+            }
+        };
+        if timeout(FLUSH_TIMEOUT, flush).await.is_err() {
+            warn!("Send task did not finish in time, aborting");
+            task.abort();
+        }
+        drop(stop);
+    }
+}
+
+/// 中继正在关闭时发给对端的关闭帧
+fn shutdown_frame(state: &RelayState) -> Option<CloseFrame> {
+    state.is_shutdown().then(|| CloseFrame {
+        code: CloseCode::Away,
+        reason: "Relay shutting down".into(),
+    })
+}
+
+/// 等待服务端放行, 放行后下发 Attached, v2 客户端另得恢复凭证
+async fn admit_client(
     state: &Arc<RelayState>,
-    tx: Tx,
-    reader: &mut SplitStream<WebSocketStream<TcpStream>>,
-) -> Result<SessionContext, &'static str> {
-    let msg = timeout(Duration::from_secs(5), reader.next())
-        .await
-        .map_err(|_| "Registry Timeout")?;
+    room: &Arc<Room>,
+    session: &Arc<Session>,
+    allow_rx: Option<oneshot::Receiver<()>>,
+    close_rx: &mut oneshot::Receiver<()>,
+) -> (bool, Option<[u8; 16]>) {
+    let Some(allow_rx) = allow_rx else {
+        return (false, None);
+    };
 
-    let incoming = match msg {
-        Some(Ok(Message::Binary(p))) => p,
-        Some(Ok(Message::Close(_))) => return Err("Channel closed"),
-        _ => return Err("Invalid register packet"),
+    info!("Client {} waiting release", session.session_id);
+    let is_allow = tokio::select! {
+        _ = allow_rx => true,
+        _ = close_rx => false,
+        _ = state.shutdown_signal() => false,
+        _ = tokio::time::sleep(state.config().permit_timeout) => false,
     };
 
+    info!("Client {} released {}", session.session_id, is_allow);
+    if !is_allow {
+        return (false, None);
+    }
+
+    room.active_client(session.session_id, session.clone());
+    let packet = Attached {
+        session_id: session.session_id,
+        version: session.version,
+    };
+    send_packet(&session.tx, packet, Duration::from_secs(2)).await;
+
+    // v1 客户端不认识恢复凭证
+    if session.version == ProtocolVersion::V1 || state.config().resume_grace.is_zero() {
+        return (true, None);
+    }
+    let token: [u8; 16] = rand::random();
+    send_packet(&session.tx, ResumeToken { token }, Duration::from_secs(2)).await;
+    (true, Some(token))
+}
+
+/// 掉线客户端在宽限期内保留席位, 返回 true 表示会话已被新连接接管
+async fn suspend_client(
+    state: &Arc<RelayState>,
+    room: &Arc<Room>,
+    session: &Arc<Session>,
+    token: [u8; 16],
+    send_task: SendTask,
+    close_rx: oneshot::Receiver<()>,
+) -> bool {
+    let Some((_, buffered)) = send_task.detach().await else {
+        return false;
+    };
+
+    let grace = state.config().resume_grace;
+    let (claimed_tx, claimed_rx) = oneshot::channel::<()>();
+    room.suspend(
+        token,
+        Suspended {
+            session_id: session.session_id,
+            buffered,
+            claimed: claimed_tx,
+        },
+    );
+    info!(
+        "Client {} suspended for {:?} awaiting resume",
+        session.session_id, grace
+    );
+
+    tokio::select! {
+        res = claimed_rx => {
+            if res.is_ok() {
+                return true;
+            }
+        }
+        _ = close_rx => {}
+        _ = state.shutdown_signal() => {}
+        _ = tokio::time::sleep(grace) => {}
+    }
+
+    // 超时与恢复同时发生时, 由取走条目的一方负责该会话
+    room.take_suspended(&token).is_none()
+}
+
+/// 服务端掉线时保留客户端, 等待新服务端接管房间, 返回 true 表示已被接管.
+/// 未开启迁移或房间内没有客户端时直接清空服务端
+async fn migrate_server(state: &Arc<RelayState>, room: &Arc<Room>) -> bool {
+    let grace = state.config().migration_grace;
+    if grace.is_zero() || room.size() == 0 || state.is_shutdown() {
+        room.clear_server().await;
+        return false;
+    }
+
+    let claimed_rx = room.begin_migration().await;
+    for entry in room.iter_clients() {
+        let session = &entry.value().session;
+        send_notice(&session.tx, session.version, NoticeCode::HostMigrating);
+    }
+    info!("Room {} awaiting a new server for {:?}", room.code(), grace);
+
+    tokio::select! {
+        res = claimed_rx => {
+            if res.is_ok() {
+                return true;
+            }
+        }
+        _ = state.shutdown_signal() => {}
+        _ = tokio::time::sleep(grace) => {}
+    }
+
+    // 超时与接管同时发生时, 由先取得服务端锁的一方决定
+    !room.abandon_migration().await
+}
+
+/// 0x01 = 注册为默认房间的 Server + 中继密钥
+/// 0x02 = 注册为默认房间的 Client + 后续字节是 client_id
+/// 0x03 = 创建房间并注册为其 Server + 中继密钥 + 房间密钥
+/// 0x04 = 注册为指定房间的 Client + 房间号 + client_id
+/// 0x05 = 恢复掉线的 Client + 房间号 + 恢复凭证 (仅 v2)
+/// 0x06 = 以入房密码注册 Client + 房间号 + client_id + 应答 (仅 v2, 见 prove_join_password)
+/// 0x07 = 接管迁移中的房间 + 房间号 + 房间密钥或迁移凭证 (仅 v2)
+/// 0x08 = 注册为旁观者 + 房间号 + client_id + 应答 (仅 v2)
+///
+/// v2 对端在头部后多带一个版本字节 0x02, 其后各帧的 session id 为 u16.
+/// 无法识别版本的注册包以 v1 文本回复错误
+async fn attach_session(
+    state: &Arc<RelayState>,
+    tx: Tx,
+    stats: Arc<SessionStats>,
+    reader: &mut WsReader,
+    address: SocketAddr,
+) -> Result<SessionContext, &'static str> {
+    let incoming = read_register_frame(state, reader).await?;
     if incoming.is_empty() {
         return Err("Empty register packet");
     }
 
+    let default_room = state.room(DEFAULT_ROOM).ok_or("Default room missing")?;
     match incoming[0] {
         REG_SERVER => {
             // 注册服务端
-            if incoming.len() != 33 {
+            let Some((version, body)) = ProtocolVersion::split_register(&incoming, 32) else {
                 return Err("Invalid server register packet");
-            }
+            };
 
-            let server = state.get_server().await;
+            let server = default_room.get_server().await;
             if server.is_some() {
-                send_message(&tx, "ERR:Server already registered");
+                send_notice(&tx, version, NoticeCode::ServerExists);
                 return Err("Server already exists");
             }
 
-            let provided_secret = &incoming[1..33];
-
             // 密钥校验
-            let state_cell = SERVER_MANAGER
-                .get()
-                .ok_or("Server manager not initialized")?;
-            let guard = state_cell.lock().await;
-            let expected_secret = guard
-                .handle
-                .as_ref()
-                .map(|h| &h.secret)
-                .ok_or("No server handle")?;
-
-            if !constant_time_eq(provided_secret, expected_secret.as_ref()) {
-                send_message(&tx, "ERR:Invalid secret");
+            if !constant_time_eq(body, default_room.secret()) {
+                send_notice(&tx, version, NoticeCode::InvalidSecret);
                 return Err("Server secret mismatch");
             }
 
-            let session_id = NEXT_SESSION_ID
-                .allocate()
-                .await
-                .ok_or("No session id allocated")?;
+            attach_server(state, default_room, tx, version, stats).await
+        }
+        REG_ROOM => {
+            // [Header][RelaySecret 32][RoomSecret 32][Salt 16][Digest 32]
+            // 末尾的入房密码摘要仅 v2 可选, 见 auth::password_digest
+            let (version, body, password) = match ProtocolVersion::split_register(&incoming, 112) {
+                Some((ProtocolVersion::V2, body)) => {
+                    let salt = body[64..80].try_into().map_err(|_| "Invalid salt")?;
+                    let digest = body[80..112].try_into().map_err(|_| "Invalid digest")?;
+                    let password = PasswordHash::from_digest(salt, digest);
+                    (ProtocolVersion::V2, &body[..64], Some(password))
+                }
+                _ => match ProtocolVersion::split_register(&incoming, 64) {
+                    Some((version, body)) => (version, body, None),
+                    None => {
+                        send_notice(&tx, ProtocolVersion::V1, NoticeCode::InvalidRegister);
+                        return Err("Invalid room register packet");
+                    }
+                },
+            };
 
-            let session = Session::new_server(tx, session_id);
-            if let Err(e) = state.register_server(session.clone()).await {
-                NEXT_SESSION_ID.deallocate(session_id).await;
-                return Err(e);
+            // 创建房间同样需要中继密钥
+            if !constant_time_eq(&body[..32], default_room.secret()) {
+                send_notice(&tx, version, NoticeCode::InvalidSecret);
+                return Err("Server secret mismatch");
             }
 
-            let packet = Attached {
-                session_id: session.session_id,
+            let mut room_secret = [0u8; 32];
+            room_secret.copy_from_slice(&body[32..64]);
+
+            let Some(room) = state.create_room(room_secret) else {
+                send_notice(&tx, version, NoticeCode::RoomLimit);
+                return Err("Room limit reached");
             };
-            send_packet(&session.tx, packet, Duration::from_secs(2)).await;
-            info!("Server registered at {}", now_ms());
-            Ok(SessionContext {
-                session,
-                allow: None,
-                close: None,
-            })
+            room.set_join_password(password);
+
+            let packet = RoomCreated {
+                room_code: room.code(),
+            };
+            send_packet(&tx, packet, Duration::from_secs(2)).await;
+            info!("Room {} created", room.code());
+
+            let ctx = attach_server(state, room.clone(), tx, version, stats).await;
+            if ctx.is_err() {
+                state.remove_room(room.code());
+            }
+            ctx
         }
         REG_CLIENT => {
             // 注册 Client
-            if incoming.len() != 17 {
-                send_message(&tx, "ERR:Invalid register packet");
+            let Some((version, body, caps)) = ProtocolVersion::split_register_caps(&incoming, 16)
+            else {
+                send_notice(&tx, ProtocolVersion::V1, NoticeCode::InvalidRegister);
                 return Err("Invalid client register packet");
-            }
+            };
 
             let mut uuid = [0u8; 16];
-            uuid.copy_from_slice(&incoming[1..17]);
-            let uuid = uuid;
-
-            // UUID重复检查
-            match state.register_client(uuid) {
-                Entry::Occupied(_) => {
-                    send_message(&tx, "ERR:Duplicate Player");
-                    Err("Duplicate client UUID")
-                }
-                Entry::Vacant(v) => {
-                    let session_id = NEXT_SESSION_ID
-                        .allocate()
-                        .await
-                        .ok_or("No session id allocated")?;
-
-                    let (permit_tx, permit_rx) = oneshot::channel::<()>();
-                    let (c_tx, c_rx) = oneshot::channel::<()>();
-                    let session = Session::new_client(tx, session_id, uuid);
-
-                    v.insert(session_id);
-                    state.insert_client_entry(session_id, session.clone(), permit_tx, c_tx);
-
-                    // 向服务端发送注册消息
-                    if let Some(server) = state.get_server().await {
-                        let packet = ClientAttached {
-                            session_id: session.session_id,
-                            uuid,
-                        };
-                        send_packet(&server.tx, packet, Duration::from_secs(2)).await;
-                    }
+            uuid.copy_from_slice(body);
 
-                    info!("Client {} registered at {}", format_uuid(&uuid), now_ms());
-                    Ok(SessionContext {
-                        session,
-                        allow: Some(permit_rx),
-                        close: Some(c_rx),
-                    })
-                }
+            check_join_password(&default_room, &tx, version)?;
+            let hello = ClientHello {
+                uuid,
+                version,
+                caps,
+            };
+            let limiter = state.client_limiter(address.ip());
+            attach_client(state, default_room, tx, hello, address, limiter, stats).await
+        }
+        REG_ROOM_CLIENT => {
+            // [Header][RoomCode u32][Uuid 16]
+            let Some((version, body, caps)) = ProtocolVersion::split_register_caps(&incoming, 20)
+            else {
+                send_notice(&tx, ProtocolVersion::V1, NoticeCode::InvalidRegister);
+                return Err("Invalid client register packet");
+            };
+
+            let mut cursor = &body[..4];
+            let room_code = cursor.get_u32_le();
+            let Some(room) = state.room(room_code) else {
+                send_notice(&tx, version, NoticeCode::RoomNotFound);
+                return Err("Room not found");
+            };
+
+            let mut uuid = [0u8; 16];
+            uuid.copy_from_slice(&body[4..20]);
+
+            check_join_password(&room, &tx, version)?;
+            let hello = ClientHello {
+                uuid,
+                version,
+                caps,
+            };
+            let limiter = state.client_limiter(address.ip());
+            attach_client(state, room, tx, hello, address, limiter, stats).await
+        }
+        REG_AUTH_CLIENT => {
+            // [Header][0x02][RoomCode u32][Uuid 16][ProofLen u8][Proof][Caps u8 可选]
+            let Some((room_code, uuid, _, caps)) = split_auth_register(&incoming) else {
+                send_notice(&tx, ProtocolVersion::V1, NoticeCode::InvalidRegister);
+                return Err("Invalid client register packet");
+            };
+
+            let Some(room) = state.room(room_code) else {
+                send_notice(&tx, ProtocolVersion::V2, NoticeCode::RoomNotFound);
+                return Err("Room not found");
+            };
+
+            prove_join_password(state, &room, &tx, reader, &incoming).await?;
+            let hello = ClientHello {
+                uuid,
+                version: ProtocolVersion::V2,
+                caps,
+            };
+            let limiter = state.client_limiter(address.ip());
+            attach_client(state, room, tx, hello, address, limiter, stats).await
+        }
+        REG_SPECTATOR => {
+            // 同 REG_AUTH_CLIENT
+            let Some((room_code, uuid, _, caps)) = split_auth_register(&incoming) else {
+                send_notice(&tx, ProtocolVersion::V1, NoticeCode::InvalidRegister);
+                return Err("Invalid spectator register packet");
+            };
+
+            let Some(room) = state.room(room_code) else {
+                send_notice(&tx, ProtocolVersion::V2, NoticeCode::RoomNotFound);
+                return Err("Room not found");
+            };
+
+            prove_join_password(state, &room, &tx, reader, &incoming).await?;
+            let hello = ClientHello {
+                uuid,
+                version: ProtocolVersion::V2,
+                caps,
+            };
+            let limiter = state.client_limiter(address.ip());
+            attach_spectator(state, room, tx, hello, address, limiter, stats).await
+        }
+        REG_RESUME => {
+            // [Header][0x02][RoomCode u32][Token 16]
+            let Some((ProtocolVersion::V2, body)) = ProtocolVersion::split_register(&incoming, 20)
+            else {
+                send_notice(&tx, ProtocolVersion::V1, NoticeCode::InvalidRegister);
+                return Err("Invalid resume packet");
+            };
+
+            let mut cursor = &body[..4];
+            let room_code = cursor.get_u32_le();
+            let Some(room) = state.room(room_code) else {
+                send_notice(&tx, ProtocolVersion::V2, NoticeCode::RoomNotFound);
+                return Err("Room not found");
+            };
+
+            let mut token = [0u8; 16];
+            token.copy_from_slice(&body[4..20]);
+
+            resume_client(room, tx, token, address).await
+        }
+        REG_MIGRATE => {
+            // [Header][0x02][RoomCode u32][RoomSecret 32 | MigrationToken 16]
+            let body = match incoming.get(1) {
+                Some(&ProtocolVersion::V2_BYTE) => &incoming[2..],
+                _ => &[][..],
+            };
+            if body.len() != 20 && body.len() != 36 {
+                send_notice(&tx, ProtocolVersion::V1, NoticeCode::InvalidRegister);
+                return Err("Invalid migrate packet");
+            }
+
+            let mut cursor = &body[..4];
+            let room_code = cursor.get_u32_le();
+            let Some(room) = state.room(room_code) else {
+                send_notice(&tx, ProtocolVersion::V2, NoticeCode::RoomNotFound);
+                return Err("Room not found");
+            };
+
+            if !room.is_migrating() {
+                send_notice(&tx, ProtocolVersion::V2, NoticeCode::MigrationFailed);
+                return Err("Room is not awaiting a new server");
+            }
+
+            let credential = &body[4..];
+            let valid = match credential.len() {
+                32 => constant_time_eq(credential, room.secret()),
+                _ => room.check_migration_token(credential),
+            };
+            if !valid {
+                send_notice(&tx, ProtocolVersion::V2, NoticeCode::InvalidSecret);
+                return Err("Migration credential mismatch");
             }
+
+            attach_server(state, room, tx, ProtocolVersion::V2, stats).await
         }
         _ => Err("Not a register packet"),
     }
 }
 
+/// 解析 [Header][0x02][RoomCode u32][Uuid 16][ProofLen u8][Proof][Caps u8 可选]
+fn split_auth_register(incoming: &[u8]) -> Option<(u32, [u8; 16], &[u8], u8)> {
+    let body = match incoming.get(1) {
+        Some(&ProtocolVersion::V2_BYTE) => &incoming[2..],
+        _ => return None,
+    };
+    let password_end = 21 + *body.get(20)? as usize;
+    let caps = match body.len().checked_sub(password_end)? {
+        0 => 0,
+        1 => body[password_end],
+        _ => return None,
+    };
+
+    let mut cursor = &body[..4];
+    let room_code = cursor.get_u32_le();
+    let mut uuid = [0u8; 16];
+    uuid.copy_from_slice(&body[4..20]);
+    Some((room_code, uuid, &body[21..password_end], caps))
+}
+
+/// 不带应答的注册方式无法进入设有入房密码的房间
+fn check_join_password(room: &Room, tx: &Tx, version: ProtocolVersion) -> Result<(), &'static str> {
+    if room.join_password().is_some() {
+        send_notice(tx, version, NoticeCode::PasswordRequired);
+        return Err("Join password required");
+    }
+    Ok(())
+}
+
+/// 带应答的注册包中 [Header][0x02][RoomCode u32][Uuid 16] 的长度
+const AUTH_REGISTER_PREFIX: usize = 22;
+
+/// 房间设有入房密码时下发 AuthChallenge, 对端须在注册期限内重发同一注册包并带上应答,
+/// 应答为 password_proof(nonce, password_digest(salt, 密码)); 首包中的应答被忽略.
+/// 在通知服务端之前拒绝
+async fn prove_join_password(
+    state: &RelayState,
+    room: &Room,
+    tx: &Tx,
+    reader: &mut WsReader,
+    first: &[u8],
+) -> Result<(), &'static str> {
+    let Some(expected) = room.join_password() else {
+        return Ok(());
+    };
+
+    let nonce = challenge_nonce();
+    let challenge = AuthChallenge {
+        salt: *expected.salt(),
+        nonce,
+    };
+    send_packet(tx, challenge, Duration::from_secs(2)).await;
+
+    let answer = read_register_frame(state, reader).await?;
+    let proof = split_auth_register(&answer)
+        .filter(|_| answer[..AUTH_REGISTER_PREFIX] == first[..AUTH_REGISTER_PREFIX])
+        .map(|(_, _, proof, _)| proof);
+    match proof {
+        None => {
+            send_notice(tx, ProtocolVersion::V2, NoticeCode::InvalidRegister);
+            Err("Invalid join password answer")
+        }
+        Some(proof) if !expected.verify_proof(&nonce, proof) => {
+            send_notice(tx, ProtocolVersion::V2, NoticeCode::WrongPassword);
+            Err("Wrong join password")
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+/// 读取注册阶段的一帧, 须在注册期限内到达
+async fn read_register_frame(
+    state: &RelayState,
+    reader: &mut WsReader,
+) -> Result<Bytes, &'static str> {
+    // 注册前对端可能已在回应 ping
+    let next_frame = async {
+        loop {
+            match reader.next().await {
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                other => return other,
+            }
+        }
+    };
+    let msg = tokio::select! {
+        msg = timeout(state.config().register_timeout, next_frame) => {
+            msg.map_err(|_| "Registry Timeout")?
+        }
+        _ = state.shutdown_signal() => return Err("Relay shutting down"),
+    };
+
+    match msg {
+        Some(Ok(Message::Binary(p))) => Ok(p),
+        Some(Ok(Message::Close(_))) => Err("Channel closed"),
+        _ => Err("Invalid register packet"),
+    }
+}
+
+async fn attach_server(
+    state: &Arc<RelayState>,
+    room: Arc<Room>,
+    tx: Tx,
+    version: ProtocolVersion,
+    stats: Arc<SessionStats>,
+) -> Result<SessionContext, &'static str> {
+    let session_id = NEXT_SESSION_ID
+        .allocate(version)
+        .await
+        .ok_or("No session id allocated")?;
+
+    let session = Session::new_server(tx, session_id, version, stats);
+    let migrated = match room.register_server(session.clone()).await {
+        Ok(migrated) => migrated,
+        Err(code) => {
+            NEXT_SESSION_ID.deallocate(session_id).await;
+            send_notice(&session.tx, version, code);
+            return Err("Server registration refused");
+        }
+    };
+
+    let packet = Attached {
+        session_id: session.session_id,
+        version,
+    };
+    send_packet(&session.tx, packet, Duration::from_secs(2)).await;
+
+    if migrated {
+        // 新服务端接管房间, 客户端无需重新注册
+        let packet = HostMigrated {
+            clients: room.collect_client_list(),
+            version,
+        };
+        send_packet(&session.tx, packet, Duration::from_secs(2)).await;
+        for entry in room.iter_clients() {
+            let client = &entry.value().session;
+            send_notice(&client.tx, client.version, NoticeCode::HostMigrated);
+        }
+        info!("Room {} migrated to a new server", room.code());
+    }
+
+    // v1 服务端不认识迁移凭证
+    if version == ProtocolVersion::V2 && !state.config().migration_grace.is_zero() {
+        let token = room.issue_migration_token();
+        send_packet(
+            &session.tx,
+            MigrationToken { token },
+            Duration::from_secs(2),
+        )
+        .await;
+    }
+    info!("Server registered in room {} at {}", room.code(), now_ms());
+    Ok(SessionContext {
+        session,
+        room,
+        allow: None,
+        close: None,
+        resumed: None,
+    })
+}
+
+async fn attach_client(
+    state: &Arc<RelayState>,
+    room: Arc<Room>,
+    tx: Tx,
+    hello: ClientHello,
+    address: SocketAddr,
+    limiter: Option<Limiter>,
+    stats: Arc<SessionStats>,
+) -> Result<SessionContext, &'static str> {
+    let (uuid, version) = (hello.uuid, hello.version);
+    if room.is_banned(&address.ip()).await {
+        info!("A banned IP attempt to connect {}", address);
+        send_notice(&tx, version, NoticeCode::Banned);
+        return Err("Banned client");
+    }
+
+    if state.size() >= state.config().max_connections {
+        send_notice(&tx, version, NoticeCode::ConnectionLimit);
+        return Err("Connection limit reached");
+    }
+
+    // v1 服务端只能识别 u8 范围的 id
+    let server = room.get_server().await;
+    let id_version = match server.as_ref() {
+        Some(s) if s.version == ProtocolVersion::V1 => ProtocolVersion::V1,
+        _ => version,
+    };
+    let session_id = NEXT_SESSION_ID
+        .allocate(id_version)
+        .await
+        .ok_or("No session id allocated")?;
+
+    let (permit_tx, permit_rx) = oneshot::channel::<()>();
+    let (c_tx, c_rx) = oneshot::channel::<()>();
+
+    // UUID重复检查; entry 持有分片写锁, 其间不得 await
+    let session = match room.register_client(uuid) {
+        Entry::Occupied(_) => {
+            send_notice(&tx, version, NoticeCode::DuplicatePlayer);
+            None
+        }
+        Entry::Vacant(v) => {
+            let session = Session::new_client(tx, session_id, &hello, address, limiter, stats);
+            v.insert(session_id);
+            room.insert_client_entry(session_id, session.clone(), permit_tx, c_tx);
+            Some(session)
+        }
+    };
+    let Some(session) = session else {
+        NEXT_SESSION_ID.deallocate(session_id).await;
+        return Err("Duplicate client UUID");
+    };
+
+    // 向服务端发送注册消息
+    if let Some(server) = server {
+        let packet = ClientAttached {
+            session_id: session.session_id,
+            uuid,
+            version: server.version,
+        };
+        send_packet(&server.tx, packet, Duration::from_secs(2)).await;
+    }
+
+    info!(
+        "Client {} registered in room {} at {}",
+        format_uuid(&uuid),
+        room.code(),
+        now_ms()
+    );
+    Ok(SessionContext {
+        session,
+        room: room.clone(),
+        allow: Some(permit_rx),
+        close: Some(c_rx),
+        resumed: None,
+    })
+}
+
+/// 旁观者无需服务端放行, 不占用玩家席位, 也不登记 UUID
+async fn attach_spectator(
+    state: &Arc<RelayState>,
+    room: Arc<Room>,
+    tx: Tx,
+    hello: ClientHello,
+    address: SocketAddr,
+    limiter: Option<Limiter>,
+    stats: Arc<SessionStats>,
+) -> Result<SessionContext, &'static str> {
+    let version = hello.version;
+    if room.is_banned(&address.ip()).await {
+        info!("A banned IP attempt to spectate {}", address);
+        send_notice(&tx, version, NoticeCode::Banned);
+        return Err("Banned spectator");
+    }
+
+    let config = state.config();
+    if state.spectator_count() >= config.max_spectators {
+        send_notice(&tx, version, NoticeCode::SpectatorLimit);
+        return Err("Spectator limit reached");
+    }
+
+    let session_id = NEXT_SESSION_ID
+        .allocate(version)
+        .await
+        .ok_or("No session id allocated")?;
+    let session = Session::new_spectator(tx, session_id, &hello, address, limiter, stats);
+    let delayed = (!config.spectator_delay.is_zero()).then(|| {
+        DelayedFeed::spawn(
+            session.tx.clone(),
+            session.stats.clone(),
+            config.spectator_delay,
+        )
+    });
+    let (c_tx, c_rx) = oneshot::channel::<()>();
+    room.insert_spectator(session.clone(), delayed, c_tx);
+
+    let packet = Attached {
+        session_id,
+        version,
+    };
+    send_packet(&session.tx, packet, Duration::from_secs(2)).await;
+
+    // v1 服务端不认识旁观者
+    if let Some(server) = room.get_server().await {
+        if server.version == ProtocolVersion::V2 {
+            let packet = SpectatorAttached {
+                session_id,
+                uuid: hello.uuid,
+            };
+            send_packet(&server.tx, packet, Duration::from_secs(2)).await;
+        }
+    }
+
+    info!(
+        "Spectator {} registered in room {} at {}",
+        format_uuid(&hello.uuid),
+        room.code(),
+        now_ms()
+    );
+    Ok(SessionContext {
+        session,
+        room,
+        allow: None,
+        close: Some(c_rx),
+        resumed: None,
+    })
+}
+
+/// 以恢复凭证接管宽限期内的掉线会话, 沿用原 session id 与 UUID
+async fn resume_client(
+    room: Arc<Room>,
+    tx: Tx,
+    token: [u8; 16],
+    address: SocketAddr,
+) -> Result<SessionContext, &'static str> {
+    if room.is_banned(&address.ip()).await {
+        info!("A banned IP attempt to resume {}", address);
+        send_notice(&tx, ProtocolVersion::V2, NoticeCode::Banned);
+        return Err("Banned client");
+    }
+
+    let Some(suspended) = room.take_suspended(&token) else {
+        send_notice(&tx, ProtocolVersion::V2, NoticeCode::ResumeFailed);
+        return Err("Unknown resume token");
+    };
+    let Some(session) = room.any_by_id(&suspended.session_id) else {
+        room.suspend(token, suspended);
+        send_notice(&tx, ProtocolVersion::V2, NoticeCode::ResumeFailed);
+        return Err("Suspended session missing");
+    };
+
+    // 原连接任务收到通知后不再清理该会话
+    let _ = suspended.claimed.send(());
+    let close = room.renew_close(&session.session_id);
+    info!(
+        "Client {} resumed in room {} from {}",
+        session.session_id,
+        room.code(),
+        address
+    );
+    Ok(SessionContext {
+        session,
+        room,
+        allow: None,
+        close,
+        resumed: Some((token, suspended.buffered)),
+    })
+}
+
+/// 连接结束的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Disconnect {
+    /// 主动关闭, 被踢出或违反协议
+    Closed,
+    /// 连接意外中断, 可在宽限期内恢复
+    Dropped,
+    /// 心跳超时, 对端已失联: 不保留席位, 也不等待写出
+    TimedOut,
+}
+
 async fn client_relay(
     state: &Arc<RelayState>,
+    room: &Arc<Room>,
     session: &Arc<Session>,
-    reader: &mut SplitStream<WebSocketStream<TcpStream>>,
-    mut close_rx: oneshot::Receiver<()>,
-) -> () {
+    reader: &mut WsReader,
+    close_rx: &mut oneshot::Receiver<()>,
+) -> Disconnect {
+    let config = state.config();
+    let mut reassembly = Reassembly::new(config.max_message_len, config.reassembly_timeout);
+    let mut heartbeat = Heartbeat::new(config.idle_timeout);
     loop {
         tokio::select! {
-            _ = & mut close_rx => break,
+            _ = &mut *close_rx => return Disconnect::Closed,
+            _ = state.shutdown_signal() => return Disconnect::Closed,
+            _ = heartbeat.expired() => {
+                info!("Client {} timed out", session.session_id);
+                return Disconnect::TimedOut;
+            }
+            _ = session.stats.relieved() => {
+                if let Some(server) = room.get_server().await {
+                    report_pressure(&server, session, false);
+                }
+            }
             msg = reader.next() => {
                 let Some(msg) = msg else {
-                    return;
+                    return Disconnect::Dropped;
                 };
-                if !on_recv_client(state, session, msg).await {
-                    break;
+                heartbeat.beat();
+                if let Err(reason) = on_recv_client(state, room, session, &mut reassembly, msg).await {
+                    return reason;
                 }
             }
         }
@@ -365,20 +1269,48 @@ async fn client_relay(
 
 async fn on_recv_client(
     state: &Arc<RelayState>,
+    room: &Arc<Room>,
     session: &Arc<Session>,
+    reassembly: &mut Reassembly,
     msg: Result<Message, Error>,
-) -> bool {
+) -> Result<(), Disconnect> {
     match msg {
         Ok(Message::Binary(payload)) => {
-            if payload.len() > MAX_PAYLOAD_LEN {
-                send_message(&session.tx, "ERR:Payload too large");
-                return false;
+            session.stats.record_in(payload.len());
+            if payload.len() > state.config().max_payload_len {
+                send_notice(&session.tx, session.version, NoticeCode::PayloadTooLarge);
+                return Err(Disconnect::Closed);
+            }
+
+            let limited = session
+                .limiter
+                .as_ref()
+                .is_some_and(|limiter| !limiter.admit(payload.len()));
+            if limited {
+                return on_rate_limited(state, session);
             }
+            let Some(payload) = reassemble(reassembly, session, payload) else {
+                return Ok(());
+            };
 
-            relay_client_message(&state, &session, payload).await
+            state.record(
+                RecordKind::ClientFrame,
+                room.code(),
+                session.session_id,
+                &payload,
+            );
+            if relay_client_message(room, session, payload).await {
+                Ok(())
+            } else {
+                Err(Disconnect::Closed)
+            }
         }
-        Ok(Message::Close(_)) => false,
-        Ok(_) => true,
+        Ok(Message::Pong(payload)) => {
+            session.stats.on_pong(&payload);
+            Ok(())
+        }
+        Ok(Message::Close(_)) => Err(Disconnect::Closed),
+        Ok(_) => Ok(()),
         Err(e) => {
             error!(
                 "WebSocket read failed from client with id={}: {}",
@@ -388,26 +1320,178 @@ async fn on_recv_client(
                     .unwrap_or_else(|| "<no-id>".to_string()),
                 e
             );
-            false
+            Err(Disconnect::Dropped)
+        }
+    }
+}
+
+/// 分片帧交给重组状态, 消息收齐时返回完整帧; 其他帧原样返回
+fn reassemble(reassembly: &mut Reassembly, session: &Session, payload: Bytes) -> Option<Bytes> {
+    if payload.first() != Some(&FRAGMENT) {
+        return Some(payload);
+    }
+
+    for message_id in reassembly.expire() {
+        warn!(
+            "Fragmented message {} from session {} timed out",
+            message_id, session.session_id
+        );
+        send_notice(
+            &session.tx,
+            session.version,
+            Notice::from(NoticeCode::FragmentTimeout).with_arg(message_id.to_string()),
+        );
+    }
+    match reassembly.push(&payload) {
+        Ok(message) => message,
+        Err(code) => {
+            warn!(
+                "Dropping fragmented message from session {}: {:?}",
+                session.session_id, code
+            );
+            send_notice(&session.tx, session.version, code);
+            None
+        }
+    }
+}
+
+/// 读取端的心跳期限, 收到任意帧 (含 pong) 即顺延; 超时为 0 时永不到期
+struct Heartbeat {
+    timeout: Duration,
+    deadline: Instant,
+}
+
+impl Heartbeat {
+    fn new(timeout: Duration) -> Self {
+        Heartbeat {
+            timeout,
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    fn beat(&mut self) {
+        self.deadline = Instant::now() + self.timeout;
+    }
+
+    async fn expired(&self) {
+        if self.timeout.is_zero() {
+            std::future::pending::<()>().await;
+        }
+        sleep_until(self.deadline).await;
+    }
+}
+
+/// 按配置的策略处理超额帧
+fn on_rate_limited(state: &Arc<RelayState>, session: &Arc<Session>) -> Result<(), Disconnect> {
+    match state.config().limit_policy {
+        LimitPolicy::Drop => Ok(()),
+        LimitPolicy::Warn => {
+            if session.limiter.as_ref().is_some_and(|l| l.should_warn()) {
+                send_notice(&session.tx, session.version, NoticeCode::RateLimited);
+            }
+            Ok(())
+        }
+        LimitPolicy::Kick => {
+            info!(
+                "Client {} kicked for exceeding rate limit",
+                session.session_id
+            );
+            send_notice(&session.tx, session.version, NoticeCode::RateLimitKicked);
+            Err(Disconnect::Closed)
+        }
+    }
+}
+
+/// 旁观者只接收, 发来的帧一律拒绝
+async fn spectator_relay(
+    state: &Arc<RelayState>,
+    session: &Arc<Session>,
+    reader: &mut WsReader,
+    close_rx: &mut oneshot::Receiver<()>,
+) -> Disconnect {
+    let mut heartbeat = Heartbeat::new(state.config().idle_timeout);
+    loop {
+        let msg = tokio::select! {
+            _ = &mut *close_rx => return Disconnect::Closed,
+            _ = state.shutdown_signal() => return Disconnect::Closed,
+            _ = heartbeat.expired() => {
+                info!("Spectator {} timed out", session.session_id);
+                return Disconnect::TimedOut;
+            }
+            msg = reader.next() => msg,
+        };
+        heartbeat.beat();
+        match msg {
+            Some(Ok(Message::Binary(payload))) => {
+                session.stats.record_in(payload.len());
+                let limited = session
+                    .limiter
+                    .as_ref()
+                    .is_some_and(|limiter| !limiter.admit(payload.len()));
+                if limited {
+                    if on_rate_limited(state, session).is_err() {
+                        return Disconnect::Closed;
+                    }
+                    continue;
+                }
+                send_notice(&session.tx, session.version, NoticeCode::SpectatorReadOnly);
+            }
+            Some(Ok(Message::Pong(payload))) => session.stats.on_pong(&payload),
+            Some(Ok(Message::Close(_))) | None => return Disconnect::Closed,
+            Some(Ok(_)) => {}
+            Some(Err(e)) => {
+                error!(
+                    "WebSocket read failed from spectator {}: {}",
+                    session.session_id, e
+                );
+                return Disconnect::Closed;
+            }
         }
     }
 }
 
 async fn server_relay(
     state: &Arc<RelayState>,
+    room: &Arc<Room>,
     session: &Arc<Session>,
-    reader: &mut SplitStream<WebSocketStream<TcpStream>>,
-) {
-    while let Some(msg) = reader.next().await {
+    reader: &mut WsReader,
+) -> Disconnect {
+    let config = state.config();
+    let mut reassembly = Reassembly::new(config.max_message_len, config.reassembly_timeout);
+    let mut heartbeat = Heartbeat::new(config.server_idle_timeout);
+    loop {
+        let msg = tokio::select! {
+            _ = state.shutdown_signal() => break,
+            _ = heartbeat.expired() => {
+                info!("Server of room {} timed out", room.code());
+                return Disconnect::TimedOut;
+            }
+            msg = reader.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+        };
+        heartbeat.beat();
         match msg {
             Ok(Message::Binary(payload)) => {
-                if payload.len() > MAX_PAYLOAD_LEN {
-                    send_message(&session.tx, "ERR:Payload too large");
+                session.stats.record_in(payload.len());
+                if payload.len() > state.config().max_payload_len {
+                    send_notice(&session.tx, session.version, NoticeCode::PayloadTooLarge);
                     break;
                 }
+                let Some(payload) = reassemble(&mut reassembly, session, payload) else {
+                    continue;
+                };
 
-                relay_server_message(&state, &session, payload).await;
+                state.record(
+                    RecordKind::ServerFrame,
+                    room.code(),
+                    session.session_id,
+                    &payload,
+                );
+                relay_server_message(state, room, session, payload).await;
             }
+            Ok(Message::Pong(payload)) => session.stats.on_pong(&payload),
             Ok(Message::Close(_)) => {
                 break;
             }
@@ -418,6 +1502,7 @@ async fn server_relay(
             }
         }
     }
+    Disconnect::Closed
 }
 
 /// 协议说明:
@@ -425,12 +1510,13 @@ async fn server_relay(
 /// 0x10 = Client -> Server
 /// 0x11 = Server -> Client 广播 + 单个排除
 /// 0x12 = Server -> Client 单发
+/// 0x15 = 为内层服务端帧声明投递类别 (可丢弃 / 必达 / 按键合并)
+/// 0x16 = Server -> 分组内的 Client
+/// 0x20 = 分片, 由读取循环重组后再进入此处
 /// 0xff = Server -> Relay 操作
-async fn relay_client_message(
-    state: &Arc<RelayState>,
-    session: &Arc<Session>,
-    payload: Bytes,
-) -> bool {
+///
+/// 帧中的 session id 按发送方版本解析, 按接收方版本重新编码
+async fn relay_client_message(room: &Arc<Room>, session: &Arc<Session>, payload: Bytes) -> bool {
     if payload.is_empty() {
         info!("Empty message received");
         return true;
@@ -439,26 +1525,43 @@ async fn relay_client_message(
     match payload[0] {
         C2S => {
             // Client → Server
-            let Some(server) = state.get_server().await else {
+            let Some(server) = room.get_server().await else {
                 return true;
             };
 
-            if payload.len() < 3 {
+            // 解析验证 sessionId
+            let Some((session_id, body)) = session.version.read_id(&payload[1..]) else {
+                warn!("InvalidPacket: Client message too short");
+                return true;
+            };
+            if body.is_empty() {
                 warn!("InvalidPacket: Client message too short");
                 return true;
             }
 
-            // 解析验证 sessionId
-            let mut cursor = &payload[1..2];
-            let session_id = cursor.get_u8();
             if session_id != session.session_id {
                 warn!("Invalid sessionId from client, dropping connection");
                 return false;
             }
 
-            let Err(e) = server.tx.try_send(payload) else {
+            let forwarded = if server.version == session.version {
+                payload
+            } else {
+                let Some(frame) = server.version.encode_frame(C2S, session_id, body) else {
+                    warn!("Session id {} exceeds server protocol", session_id);
+                    return true;
+                };
+                frame
+            };
+
+            let Err(e) = server.tx.try_send(forwarded.into()) else {
                 return true;
             };
+            // 服务端管道已满时丢弃此帧, 不因服务端拥塞断开客户端
+            if let TrySendError::Full(_) = e {
+                server.stats.record_drop();
+                return true;
+            }
             error!(
                 "Failed to forward message from Client {}: {}",
                 session
@@ -473,20 +1576,52 @@ async fn relay_client_message(
     }
 }
 
-async fn relay_server_message(state: &Arc<RelayState>, session: &Arc<Session>, payload: Bytes) {
+async fn relay_server_message(
+    state: &Arc<RelayState>,
+    room: &Arc<Room>,
+    session: &Arc<Session>,
+    payload: Bytes,
+) {
+    if payload.is_empty() {
+        info!("Empty message received");
+        return;
+    }
+
+    let (delivery, payload) = match Delivery::split(&payload) {
+        Some(classed) => classed,
+        None if payload[0] == SERVER_CLASSED => {
+            warn!("InvalidPacket: Unknown delivery class");
+            return;
+        }
+        None => (Delivery::Droppable, payload),
+    };
     if payload.is_empty() {
         info!("Empty message received");
         return;
     }
 
+    let compress = state.config().should_compress(payload.len());
+
+    let server = session;
+    let version = session.version;
     match payload[0] {
         SERVER_BROADCAST => {
             // [Header][Id][Data]
             // Server → 广播给所有 Client
+            let Some((id, body)) = version.read_id(&payload[1..]) else {
+                warn!("InvalidPacket: Broadcast packet too short");
+                return;
+            };
+            let mut frame = VersionedFrame::new(SERVER_BROADCAST, id, body)
+                .with_original(version, payload.clone());
+
             let mut to_close = Vec::new();
-            for entry in state.iter() {
+            for entry in room.iter() {
                 let session = entry.value();
-                if send_or_drop(&session.tx, &payload) {
+                let Some(forwarded) = frame_for(&mut frame, session, compress) else {
+                    continue;
+                };
+                if deliver(server, session, forwarded.clone(), delivery) {
                     continue;
                 }
 
@@ -500,72 +1635,86 @@ async fn relay_server_message(state: &Arc<RelayState>, session: &Arc<Session>, p
             }
 
             for id in to_close {
-                state.close(&id);
+                room.close(&id);
             }
+            feed_spectators(room, &mut frame, compress, delivery);
         }
         SERVER_SINGLE => {
             // [Header][TargetId][Data]
             // Server → 指定 Client SessionId
-            if payload.len() < 2 {
+            let Some((target_id, body)) = version.read_id(&payload[1..]) else {
                 warn!("InvalidPacket: Unicast packet too short");
                 return;
-            }
+            };
 
-            // SessionId
-            let target_id = payload[1];
-            let Some(session) = state.by_id(&target_id) else {
+            let Some(session) = room.by_id(&target_id) else {
                 return;
             };
-            if send_or_drop_move(&session.tx, payload) {
+            let forwarded = if session.version == version {
+                payload
+            } else {
+                let Some(frame) = session.version.encode_frame(SERVER_SINGLE, target_id, body)
+                else {
+                    return;
+                };
+                frame
+            };
+            if deliver(server, &session, forwarded, delivery) {
                 return;
             }
-            state.close(&target_id);
+            room.close(&target_id);
         }
         SERVER_SINGLE_UUID => {
             // [Header][Id][TargetUuid][Data]
             // Server → 指定 Client UUID
-            if payload.len() < 18 {
+            let Some((_, rest)) = version.read_id(&payload[1..]) else {
+                warn!("InvalidPacket: Unicast packet too short");
+                return;
+            };
+            if rest.len() < 16 {
                 warn!("InvalidPacket: Unicast packet too short");
                 return;
             }
 
             // UUID截断
             let mut target_client_id = [0u8; 16];
-            target_client_id.copy_from_slice(&payload[2..18]);
+            target_client_id.copy_from_slice(&rest[..16]);
             let target_client_id = target_client_id;
 
             if is_nil_uuid(&target_client_id) {
                 return;
             }
 
-            let Some(session) = state.by_uuid(&target_client_id) else {
+            let Some(session) = room.by_uuid(&target_client_id) else {
                 return;
             };
 
-            let remaining = payload.slice(18..);
-
-            let mut buf = BytesMut::with_capacity(2 + remaining.len());
             // 客户端不需要路由语义, 这里是故意设计的
-            buf.put_u8(SERVER_BROADCAST);
-            buf.put_u8(session.session_id);
-            buf.put_slice(&remaining);
-            let forwarded = buf.freeze();
+            let Some(forwarded) =
+                session
+                    .version
+                    .encode_frame(SERVER_BROADCAST, session.session_id, &rest[16..])
+            else {
+                return;
+            };
 
-            if send_or_drop_move(&session.tx, forwarded) {
+            if deliver(server, &session, forwarded, delivery) {
                 return;
             }
-            state.close(&session.session_id);
+            room.close(&session.session_id);
         }
         SERVER_EXCLUDE => {
             // [Header][Id][TargetIds][Data]
             // Server → 广播给未被排除的 Client
-            if payload.len() < 3 {
+            let Some((_, mut cursor)) = version.read_id(&payload[1..]) else {
+                warn!("InvalidPacket: BroadcastExcluding too short");
+                return;
+            };
+            if cursor.is_empty() {
                 warn!("InvalidPacket: BroadcastExcluding too short");
                 return;
             }
 
-            let mut cursor = &payload[2..];
-
             let (count, remaining) = match read_var_uint(cursor) {
                 Ok(v) => v,
                 Err(e) => {
@@ -574,8 +1723,8 @@ async fn relay_server_message(state: &Arc<RelayState>, session: &Arc<Session>, p
                 }
             };
 
-            if count > MAX_EXCLUDES {
-                send_message(&session.tx, "ERR:Exclude list too large");
+            if count as usize > state.config().max_excludes {
+                send_notice(&session.tx, session.version, NoticeCode::ExcludeTooLarge);
                 warn!("InvalidPacket: Exclude list too large");
                 return;
             }
@@ -583,7 +1732,7 @@ async fn relay_server_message(state: &Arc<RelayState>, session: &Arc<Session>, p
             cursor = remaining;
 
             // 解析 id
-            let (excludes, rest_payload) = match parse_session_id(cursor, count as usize) {
+            let (excludes, rest_payload) = match parse_session_id(cursor, count as usize, version) {
                 Ok(v) => v,
                 Err(e) => {
                     info!("Parse exclude id error: {}", e);
@@ -591,21 +1740,20 @@ async fn relay_server_message(state: &Arc<RelayState>, session: &Arc<Session>, p
                 }
             };
 
-            let mut buf = BytesMut::with_capacity(2 + rest_payload.len());
-            buf.put_u8(SERVER_BROADCAST);
-            buf.put_u8(session.session_id);
-            buf.put_slice(rest_payload);
-            let forwarded = buf.freeze();
+            let mut frame = VersionedFrame::new(SERVER_BROADCAST, session.session_id, rest_payload);
 
             let mut to_close = Vec::new();
-            for entry in state.iter() {
+            for entry in room.iter() {
                 let id = *entry.key();
                 if excludes.iter().any(|ex| ex == &id) {
                     continue;
                 }
 
                 let session = entry.value();
-                if send_or_drop(&session.tx, &forwarded) {
+                let Some(forwarded) = frame_for(&mut frame, session, compress) else {
+                    continue;
+                };
+                if deliver(server, session, forwarded.clone(), delivery) {
                     continue;
                 }
 
@@ -619,86 +1767,305 @@ async fn relay_server_message(state: &Arc<RelayState>, session: &Arc<Session>, p
             }
 
             for id in to_close {
-                state.close(&id);
+                room.close(&id);
+            }
+            feed_spectators(room, &mut frame, compress, delivery);
+        }
+        SERVER_GROUP => {
+            // [Header][Id][Group u16][Data]
+            // Server → 分组内的 Client, 下发时与广播帧格式相同
+            let Some((id, rest)) = version.read_id(&payload[1..]) else {
+                warn!("InvalidPacket: Group packet too short");
+                return;
+            };
+            let Some((group, body)) = rest.split_first_chunk::<2>() else {
+                warn!("InvalidPacket: Group packet too short");
+                return;
+            };
+            let Some(members) = room.group_members(u16::from_le_bytes(*group)) else {
+                return;
+            };
+
+            let mut frame = VersionedFrame::new(SERVER_BROADCAST, id, body);
+            let mut to_close = Vec::new();
+            for member in members {
+                let Some(session) = room.by_id(&member) else {
+                    continue;
+                };
+                let Some(forwarded) = frame_for(&mut frame, &session, compress) else {
+                    continue;
+                };
+                if deliver(server, &session, forwarded.clone(), delivery) {
+                    continue;
+                }
+
+                if let Some(uuid) = session.uuid {
+                    warn!(
+                        "[Group] Dropping unresponsive client {}",
+                        format_uuid(&uuid)
+                    );
+                }
+                to_close.push(member);
+            }
+
+            for id in to_close {
+                room.close(&id);
             }
         }
-        SERVER_ACTION => relay_actions(state, session, payload).await,
+        SERVER_ACTION => relay_actions(room, session, payload).await,
         _ => {}
     }
 }
 
-async fn relay_actions(state: &Arc<RelayState>, session: &Arc<Session>, payload: Bytes) -> () {
+async fn relay_actions(room: &Arc<Room>, session: &Arc<Session>, payload: Bytes) -> () {
     // 协议格式: [Header 0xff][Type 1][Data n]
     // Action 类型表:
-    //   0x00 = Kick         [session_id 1]         踢出指定客户端
-    //   0x01 = Permit       [session_id 1]         放行客户端流量
-    //   0x02 = QueryClients (no data)              查询当前在线客户端列表
+    //   0x00 = Kick         [session_id 1|2]       踢出指定客户端
+    //   0x01 = Permit       [session_id 1|2]       放行客户端流量
+    //   0x02 = QueryClients (no data | flags 1)    查询当前在线客户端列表, 可附带 RTT 等字段
+    //   0x03 = BanIp        [ip 4|5|16|17]         封禁地址或地址段, 格式见 IpRange::parse_action (v1 见 parse_action_v1)
+    //   0x04 = UnbanIp      [ip 4|5|16|17]         解除封禁
+    //   0x05 = QueryLimits  (no data)              查询客户端限流计数
+    //   0x06 = QueryStats   (no data)              查询房间内各连接的流量
+    //   0x07 = QueryBans    (no data)              查询封禁列表
+    //   0x08 = GroupJoin    [group u16][session_id 1|2]*  将客户端加入分组, 分组不存在时创建
+    //   0x09 = GroupLeave   [group u16][session_id 1|2]*  将客户端移出分组
+    //   0x0A = GroupRemove  [group u16]            解散分组
+    //   0x0B = KickWithReason [session_id 1|2][reason UTF-8]  踢出并告知原因
+    //   0x0C = BanSession   [session_id 1|2][expiry_secs u32 可选]  封禁该会话的地址并踢出, 无到期时间为永久封禁
+    //   0x0D = Deny         [session_id 1|2][reason UTF-8]  拒绝等待放行的客户端
     if payload.len() < 2 {
-        action_fail(&session.tx, "Invalid action packet").await;
+        action_fail(session, NoticeCode::InvalidAction).await;
         return;
     }
 
     let data = &payload[2..];
+    let version = session.version;
     match payload[1] {
         KICK => {
-            if data.len() != 1 {
-                action_fail(&session.tx, "[Kick] Session id cannot be empty").await;
+            let Some((session_id, [])) = version.read_id(data) else {
+                action_fail(
+                    session,
+                    Notice::from(NoticeCode::MissingSessionId).with_arg("Kick"),
+                )
+                .await;
                 return;
-            }
+            };
 
-            let session_id = data[0];
-            if let Some(session) = state.any_by_id(&session_id) {
-                send_message(&session.tx, "INFO:Kicked");
-                state.close(&session_id);
+            if let Some(session) = room.any_by_id(&session_id) {
+                send_notice(&session.tx, session.version, NoticeCode::Kicked);
+                room.close(&session_id);
             }
         }
         PERMIT => {
-            if data.len() != 1 {
-                action_fail(&session.tx, "[Permit] Session id cannot be empty").await;
+            let Some((session_id, [])) = version.read_id(data) else {
+                action_fail(
+                    session,
+                    Notice::from(NoticeCode::MissingSessionId).with_arg("Permit"),
+                )
+                .await;
                 return;
-            }
-            state.permit(&data[0]);
+            };
+            room.permit(&session_id);
+        }
+        // v1 回包无法表示较大的 id, 不返回缺项的列表
+        QUERY if room.has_unaddressable(version) => {
+            action_fail(session, NoticeCode::UnaddressableClients).await
         }
-        QUERY => {
+        QUERY => match data {
             // QueryClients: 查询当前所有在线客户端列表
-            // 回包格式: [0x00][0x04][count u8]([session_id u8][uuid 16B])*
-            let clients = state.collect_client_list();
-            let result = QueryClientsResult { clients };
+            // 回包格式: [0x00][0x04][count u8|u16]([session_id u8|u16][uuid 16B])*
+            [] => {
+                let clients = room.collect_client_list();
+                let result = QueryClientsResult { clients, version };
+                send_packet(&session.tx, result, Duration::from_secs(2)).await;
+            }
+            // 带标志位时回包为 QueryClientsExtResult
+            [flags] => {
+                let clients = room.collect_client_details();
+                let result = QueryClientsExtResult {
+                    flags: *flags,
+                    clients,
+                    version,
+                };
+                send_packet(&session.tx, result, Duration::from_secs(2)).await;
+            }
+            _ => action_fail(session, NoticeCode::InvalidQueryFlags).await,
+        },
+        QUERY_LIMITS => {
+            // 回包格式: [0x00][0x07][count u8|u16]([session_id u8|u16][frames u64][bytes u64])*
+            let clients = room.collect_limit_stats();
+            let result = RateLimitStats { clients, version };
+            send_packet(&session.tx, result, Duration::from_secs(2)).await;
+        }
+        QUERY_STATS => {
+            // 回包格式见 TrafficStatsResult
+            let sessions = room.collect_traffic().await;
+            let result = TrafficStatsResult { sessions, version };
             send_packet(&session.tx, result, Duration::from_secs(2)).await;
         }
         BAN_IP => {
-            let addr = parse_ipv4(data);
-            let Some(ip) = addr else {
-                action_fail(&session.tx, "[Ban] Ipv4 syntax error").await;
+            let Some(range) = parse_ip_action(session.version, data) else {
+                action_fail(session, NoticeCode::InvalidIp).await;
                 return;
             };
 
-            state.ban(ip.into()).await;
+            room.ban(range).await;
         }
         UNBAN_IP => {
-            let addr = parse_ipv4(data);
-            let Some(ip) = addr else {
-                action_fail(&session.tx, "[Ban] Ipv4 syntax error").await;
+            let Some(range) = parse_ip_action(session.version, data) else {
+                action_fail(session, NoticeCode::InvalidIp).await;
+                return;
+            };
+
+            if room.unban(&range).await {
+                send_notice(&session.tx, session.version, NoticeCode::Unbanned);
+            } else {
+                send_notice(&session.tx, session.version, NoticeCode::NotBanned);
+            }
+        }
+        QUERY_BANS => {
+            // 回包格式见 BanList
+            let bans = room.bans().await;
+            send_packet(&session.tx, BanList { bans }, Duration::from_secs(2)).await;
+        }
+        KICK_WITH_REASON => {
+            let (session_id, reason) = match split_reason(data, version, "Kick") {
+                Ok(v) => v,
+                Err(notice) => {
+                    action_fail(session, notice).await;
+                    return;
+                }
+            };
+
+            if let Some(session) = room.any_by_id(&session_id) {
+                send_notice(
+                    &session.tx,
+                    session.version,
+                    with_reason(NoticeCode::Kicked, reason),
+                );
+                room.close(&session_id);
+            }
+        }
+        BAN_SESSION => {
+            let (session_id, expiry) = match version.read_id(data) {
+                Some((session_id, [])) => (session_id, 0),
+                Some((session_id, &[a, b, c, d])) => (session_id, u32::from_le_bytes([a, b, c, d])),
+                _ => {
+                    action_fail(
+                        session,
+                        Notice::from(NoticeCode::MissingSessionId).with_arg("Ban"),
+                    )
+                    .await;
+                    return;
+                }
+            };
+
+            let Some(target) = room.any_by_id(&session_id) else {
+                action_fail(session, NoticeCode::SessionNotFound).await;
+                return;
+            };
+            let Some(address) = target.address else {
+                action_fail(session, NoticeCode::SessionNotFound).await;
                 return;
             };
 
-            if state.unban(&ip.into()).await {
-                send_message(&session.tx, "INFO:Unban");
+            let range = IpRange::single(address.ip());
+            if expiry == 0 {
+                room.ban(range).await;
             } else {
-                send_message(&session.tx, "INFO:This ip is not banned");
+                let expires = Instant::now() + Duration::from_secs(expiry as u64);
+                room.ban_until(range, expires).await;
+            }
+            info!("Session {} banned by address {}", session_id, range);
+            send_notice(&target.tx, target.version, NoticeCode::Banned);
+            room.close(&session_id);
+        }
+        DENY => {
+            let (session_id, reason) = match split_reason(data, version, "Deny") {
+                Ok(v) => v,
+                Err(notice) => {
+                    action_fail(session, notice).await;
+                    return;
+                }
+            };
+
+            if !room.is_pending(&session_id) {
+                action_fail(session, NoticeCode::NotPending).await;
+                return;
+            }
+            if let Some(session) = room.any_by_id(&session_id) {
+                send_notice(
+                    &session.tx,
+                    session.version,
+                    with_reason(NoticeCode::Denied, reason),
+                );
+                room.close(&session_id);
+            }
+        }
+        GROUP_JOIN | GROUP_LEAVE => {
+            let Some((group, ids)) = split_group_action(data, version) else {
+                action_fail(session, NoticeCode::InvalidGroup).await;
+                return;
+            };
+
+            if payload[1] == GROUP_LEAVE {
+                room.leave_group(group, &ids);
+            } else if !room.join_group(group, &ids) {
+                action_fail(session, NoticeCode::GroupLimit).await;
             }
         }
+        GROUP_REMOVE => {
+            let [lo, hi] = data else {
+                action_fail(session, NoticeCode::InvalidGroup).await;
+                return;
+            };
+            room.remove_group(u16::from_le_bytes([*lo, *hi]));
+        }
         _ => {
             warn!("Invalid action type: 0x{:02x}", payload[1]);
-            action_fail(&session.tx, "Unknown action type").await;
+            action_fail(session, NoticeCode::UnknownAction).await;
         }
     };
 }
 
+/// 解析 [session_id 1|2][reason UTF-8], 原因可为空
+fn split_reason<'a>(
+    data: &'a [u8],
+    version: ProtocolVersion,
+    action: &str,
+) -> Result<(u16, &'a str), Notice> {
+    let (session_id, reason) = version
+        .read_id(data)
+        .ok_or_else(|| Notice::from(NoticeCode::MissingSessionId).with_arg(action))?;
+    let reason =
+        std::str::from_utf8(reason).map_err(|_| Notice::from(NoticeCode::InvalidReason))?;
+    Ok((session_id, reason))
+}
+
+fn with_reason(code: NoticeCode, reason: &str) -> Notice {
+    let notice = Notice::from(code);
+    if reason.is_empty() {
+        notice
+    } else {
+        notice.with_arg(reason)
+    }
+}
+
+/// 解析 [group u16][session_id 1|2]*
+fn split_group_action(data: &[u8], version: ProtocolVersion) -> Option<(u16, Vec<u16>)> {
+    let (group, ids) = data.split_first_chunk::<2>()?;
+    if ids.len() % version.id_len() != 0 {
+        return None;
+    }
+    let (ids, _) = parse_session_id(ids, ids.len() / version.id_len(), version).ok()?;
+    Some((u16::from_le_bytes(*group), ids))
+}
+
 /// 中继服务器发送
 async fn send_packet<T: Payload>(tx: &Tx, payload: T, timeout: Duration) -> () {
     let buf = payload.to_bytes();
-    match tx.send_timeout(buf, timeout).await {
+    match tx.send_timeout(buf.into(), timeout).await {
         Ok(()) => {}
         Err(e) => {
             error!("Failed to send relay: {}", e);
@@ -708,45 +2075,248 @@ async fn send_packet<T: Payload>(tx: &Tx, payload: T, timeout: Duration) -> () {
 
 fn try_send_packet<T: Payload>(tx: &Tx, payload: T) -> () {
     let buf = payload.to_bytes();
-    let _ = tx.try_send(buf);
+    let _ = tx.try_send(buf.into());
+}
+
+/// v1 服务端的 BAN_IP/UNBAN_IP 数据兼容旧的宽松格式
+fn parse_ip_action(version: ProtocolVersion, data: &[u8]) -> Option<IpRange> {
+    match version {
+        ProtocolVersion::V1 => IpRange::parse_action_v1(data),
+        ProtocolVersion::V2 => IpRange::parse_action(data),
+    }
 }
 
-/// 区别于 send_message.
+/// 区别于 send_notice.
 /// 此方法只能向服务端发送
-async fn action_fail(tx: &Tx, reason: &str) -> () {
-    let packet = RelayMessage {
-        message: reason.to_string(),
-    };
-    send_packet(tx, packet, Duration::from_secs(2)).await;
+async fn action_fail(session: &Session, notice: impl Into<Notice>) {
+    let notice = notice.into();
+    match session.version {
+        ProtocolVersion::V1 => {
+            let packet = RelayMessage {
+                message: notice.fallback(),
+            };
+            send_packet(&session.tx, packet, Duration::from_secs(2)).await;
+        }
+        ProtocolVersion::V2 => {
+            let packet = RelayNotice { notice };
+            send_packet(&session.tx, packet, Duration::from_secs(2)).await;
+        }
+    }
 }
 
 /// 中继通知,目前为纯文本
-fn send_message(tx: &Tx, reason: &str) -> () {
-    let packet = RelayMessage {
-        message: reason.to_string(),
-    };
-    try_send_packet(tx, packet);
+/// v1 对端只认识 RelayMessage, 收到通知的回退文本
+fn send_notice(tx: &Tx, version: ProtocolVersion, notice: impl Into<Notice>) {
+    let notice = notice.into();
+    match version {
+        ProtocolVersion::V1 => try_send_packet(
+            tx,
+            RelayMessage {
+                message: notice.fallback(),
+            },
+        ),
+        ProtocolVersion::V2 => try_send_packet(tx, RelayNotice { notice }),
+    }
 }
 
-/// 广播
-fn send_or_drop(tx: &Tx, payload: &Bytes) -> bool {
-    match tx.try_send(payload.clone()) {
-        Ok(_) => true,
-        Err(TrySendError::Full(_)) => {
-            warn!("Payload drop because channel full");
-            true
+/// 扇出时为接收方挑选帧: 帧足够大且接收方声明支持压缩时发送压缩帧
+fn frame_for<'f>(
+    frame: &'f mut VersionedFrame<'_>,
+    session: &Session,
+    compress: bool,
+) -> Option<&'f Bytes> {
+    if compress && session.compression {
+        frame.get_compressed(session.version)
+    } else {
+        frame.get(session.version)
+    }
+}
+
+/// 广播同时转发给旁观者, 旁观者不参与排除列表与背压通知
+fn feed_spectators(
+    room: &Room,
+    frame: &mut VersionedFrame<'_>,
+    compress: bool,
+    delivery: Delivery,
+) {
+    let mut to_close = Vec::new();
+    for entry in room.iter_spectators() {
+        let spectator = entry.value();
+        let session = &spectator.session;
+        let Some(forwarded) = frame_for(frame, session, compress) else {
+            continue;
+        };
+        let delivered = match spectator.delayed.as_ref() {
+            Some(feed) => {
+                if !feed.push(forwarded.clone()) {
+                    session.stats.record_drop();
+                }
+                true
+            }
+            None => enqueue(session, forwarded.clone(), delivery),
+        };
+        if !delivered {
+            to_close.push(*entry.key());
         }
-        Err(TrySendError::Closed(_)) => false,
     }
+
+    for id in to_close {
+        room.close(&id);
+    }
+}
+
+/// 按投递类别入队, 仅在接收方已关闭或必达帧无法入队时返回 false
+fn deliver(server: &Session, session: &Session, frame: Bytes, delivery: Delivery) -> bool {
+    let delivered = enqueue(session, frame, delivery);
+    if delivered
+        && session.queue_depth() >= high_watermark(session.tx.max_capacity())
+        && session.stats.enter_congestion()
+    {
+        report_pressure(server, session, true);
+    }
+    delivered
 }
 
-fn send_or_drop_move(tx: &Tx, payload: Bytes) -> bool {
-    match tx.try_send(payload) {
+/// 队列余量低于容量的 1/4 时丢弃可丢弃帧, 为必达帧留出空间
+fn reliable_reserve(capacity: usize) -> usize {
+    capacity / 4
+}
+
+/// 客户端发送队列越过容量的 1/2 与回落到 1/8 时通知服务端
+fn high_watermark(capacity: usize) -> usize {
+    capacity / 2
+}
+
+fn low_watermark(capacity: usize) -> usize {
+    capacity / 8
+}
+
+fn enqueue(session: &Session, frame: Bytes, delivery: Delivery) -> bool {
+    let item = match delivery {
+        Delivery::Reliable => {
+            return match session.tx.try_send(frame.into()) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Reliable payload could not be queued");
+                    session.stats.record_drop();
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            };
+        }
+        Delivery::Latest(key) => match session.latest.replace(key, frame) {
+            Some(slot) => Outgoing::Latest(slot),
+            None => return true,
+        },
+        Delivery::Droppable => frame.into(),
+    };
+
+    if session.tx.capacity() < reliable_reserve(session.tx.max_capacity()) {
+        warn!("Payload drop because channel full");
+        session.stats.record_drop();
+        // 清空合并槽, 以免后续同键的帧写入一个不会被写出的槽
+        item.into_frame();
+        return !session.tx.is_closed();
+    }
+    match session.tx.try_send(item) {
         Ok(_) => true,
-        Err(TrySendError::Full(_)) => {
+        Err(TrySendError::Full(item)) => {
             warn!("Payload drop because channel full");
+            session.stats.record_drop();
+            item.into_frame();
             true
         }
         Err(TrySendError::Closed(_)) => false,
     }
 }
+
+/// 向 v2 服务端通知客户端发送队列的水位变化
+fn report_pressure(server: &Session, session: &Session, congested: bool) {
+    if server.version != ProtocolVersion::V2 {
+        return;
+    }
+    try_send_packet(
+        &server.tx,
+        Backpressure {
+            session_id: session.session_id,
+            congested,
+            depth: session.queue_depth().min(u16::MAX as usize) as u16,
+            dropped: session.stats.take_unreported_drops().min(u32::MAX as u64) as u32,
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::config::RelayConfig;
+    use crate::network::relay::{local_port, start_relay, stop_relay};
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio_tungstenite::connect_async;
+
+    /// 对端既不读取也不回应 ping 时, 心跳超时须立即断开并通知服务端, 而不是进入恢复宽限期
+    #[tokio::test(flavor = "multi_thread")]
+    async fn heartbeat_timeout_detaches_unresponsive_client() {
+        // 由系统分配端口, 避免与其它进程冲突
+        let mut config = RelayConfig::with_port(0);
+        config.bind_addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        config.ping_interval = Duration::from_millis(500);
+        config.idle_timeout = Duration::from_secs(2);
+        config.max_payload_len = 64 * 1024;
+        config.max_message_len = 64 * 1024;
+        config.compress_threshold = 0;
+        assert!(!config.resume_grace.is_zero());
+        let (secret, _task) = start_relay(config, None, None, None).await.unwrap();
+        let url = format!("ws://127.0.0.1:{}", local_port().await.unwrap());
+
+        // 服务端持续读取, 以便回应 ping
+        let (server, _) = connect_async(&url).await.unwrap();
+        let (mut server_tx, mut server_rx) = server.split();
+        let mut register = vec![REG_SERVER];
+        register.extend_from_slice(&secret);
+        server_tx.send(Message::binary(register)).await.unwrap();
+        let (frames_tx, mut frames) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(Ok(msg)) = server_rx.next().await {
+                if let Message::Binary(frame) = msg {
+                    let _ = frames_tx.send(frame);
+                }
+            }
+        });
+        let attached = frames.recv().await.unwrap();
+        assert_eq!(attached[..2], [0x00, Attached::PAYLOAD_TYPE]);
+
+        // v2 客户端会拿到恢复凭证
+        let (mut client, _) = connect_async(&url).await.unwrap();
+        let mut register = vec![REG_CLIENT, 0x02];
+        register.extend_from_slice(&[7u8; 16]);
+        client.send(Message::binary(register)).await.unwrap();
+        let joined = frames.recv().await.unwrap();
+        assert_eq!(joined[..2], [0x00, ClientAttached::PAYLOAD_TYPE]);
+        let session_id = joined[2];
+        server_tx
+            .send(Message::binary(vec![SERVER_ACTION, PERMIT, session_id]))
+            .await
+            .unwrap();
+        let attached = client.next().await.unwrap().unwrap().into_data();
+        assert_eq!(attached[..2], [0x00, Attached::PAYLOAD_TYPE]);
+
+        // 客户端此后不再读取; 灌满其连接, 使中继的写出任务卡在写出上
+        let mut broadcast = vec![SERVER_BROADCAST, 0x00];
+        broadcast.resize(60 * 1024, 0xAB);
+        let broadcast = Bytes::from(broadcast);
+        let started = Instant::now();
+        let detached = loop {
+            let _ = server_tx.send(Message::Binary(broadcast.clone())).await;
+            if let Ok(frame) = frames.try_recv() {
+                break frame;
+            }
+            assert!(started.elapsed() < Duration::from_secs(8), "no Detached");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+        assert_eq!(detached[..], [0x00, Detached::PAYLOAD_TYPE, session_id]);
+
+        drop(client);
+        stop_relay().await;
+    }
+}